@@ -0,0 +1,188 @@
+use resources::{Component, Resources, ResourceRequest};
+use storage::Storage;
+use entity::Entity;
+
+/// A structural change that is applied once no system holds a loan
+pub(crate) type Command = Box<FnOnce(&Resources) + Send>;
+
+/*************************************************/
+/* Queues structural changes made by a system    */
+/*************************************************/
+/// Commands let a system spawn entities and add or remove components without
+/// holding a write loan on the collections involved. The commands are applied
+/// once the dispatcher finishes its update, ordered by the order the systems
+/// were added and then by the order they were queued
+pub struct Commands<'a> {
+    resources : &'a Resources,
+    queue : Vec<Command>,
+}
+
+impl<'a> Commands<'a> {
+    pub(crate) fn new(resources : &'a Resources) -> Commands<'a> {
+        Commands {
+            resources : resources,
+            queue : Vec::new(),
+        }
+    }
+
+    /// Reserves a new Entity right away, the entity has no
+    /// components until the queued inserts are applied
+    pub fn spawn(&mut self) -> Entity {
+        self.resources.allocate()
+    }
+
+    /// Queues adding the component to the entity, the component is
+    /// dropped if the entity was deleted before the command is applied
+    pub fn insert<C : Component>(&mut self, entity : Entity, component : C) -> &mut Self {
+        self.queue.push(Box::new(move |resources : &Resources| {
+            if !resources.is_alive(&entity) {
+                return;
+            }
+            resources.register::<C>();
+            let mut request = ResourceRequest::new();
+            request.write::<C>();
+            let token = resources.get_token().request(&request).unwrap();
+            token.unpack_mut::<C>().unwrap().insert(entity.id(), component);
+        }));
+        self
+    }
+
+    /// Queues removing the component from the entity
+    pub fn remove<C : Component>(&mut self, entity : Entity) -> &mut Self {
+        self.queue.push(Box::new(move |resources : &Resources| {
+            if !resources.contains::<C>() {
+                return;
+            }
+            let mut request = ResourceRequest::new();
+            request.write::<C>();
+            let token = resources.get_token().request(&request).unwrap();
+            token.unpack_mut::<C>().unwrap().remove(entity.id());
+        }));
+        self
+    }
+
+    /// Queues deleting the entity along with all of its components
+    pub fn despawn(&mut self, entity : Entity) -> &mut Self {
+        self.queue.push(Box::new(move |resources : &Resources| {
+            resources.delete_entity(entity);
+        }));
+        self
+    }
+
+    /// Queues removing every component of an entity that was already killed
+    pub(crate) fn remove_components(&mut self, entity : Entity) -> &mut Self {
+        self.queue.push(Box::new(move |resources : &Resources| {
+            resources.remove_components(entity);
+        }));
+        self
+    }
+
+    /// Returns the number of queued commands
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Returns true if no commands are queued
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Takes the queued commands out of the buffer
+    pub(crate) fn take(&mut self) -> Vec<Command> {
+        self.queue.drain(..).collect()
+    }
+}
+
+/*************************************************/
+/* Unit Tests                                    */
+/*************************************************/
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use resources::{ResourceToken, ComponentVector};
+    use systems::System;
+    use dispatcher::{Dispatcher, DispatchMode};
+    use state::Trans;
+
+    struct Tag(u32);
+    impl Component for Tag {
+        type Storage = ComponentVector<Self>;
+    }
+
+    struct Tagger {
+        request : ResourceRequest,
+        entity : Arc<Mutex<Option<Entity>>>,
+        tag : u32,
+    }
+    impl System for Tagger {
+        fn resources(&self) -> Option<&ResourceRequest> {
+            Some(&self.request)
+        }
+
+        fn update(&mut self, token : ResourceToken) -> Trans {
+            // the read loan would block a write, the insert is deferred instead
+            let token = token.request(&self.request).unwrap();
+            let mut entity = self.entity.lock().unwrap();
+            let entity = *entity.get_or_insert_with(|| token.commands().spawn());
+            token.commands().insert(entity, Tag(self.tag));
+            Trans::None
+        }
+    }
+
+    fn tagged(resources : &Resources, entity : Entity) -> Option<u32> {
+        let mut request = ResourceRequest::new();
+        request.read::<Tag>();
+        let token = resources.get_token().request(&request).unwrap();
+        token.get::<Tag>(entity).unwrap().map(|tag| tag.0)
+    }
+
+    #[test]
+    fn test_apply_order() {
+        for mode in vec!(DispatchMode::Parallel, DispatchMode::Sequential) {
+            let resources = Arc::new(Resources::new());
+            resources.register::<Tag>();
+            let entity = Arc::new(Mutex::new(None));
+            let mut dispatcher = Dispatcher::new();
+            dispatcher.set_mode(mode);
+            for tag in 1..5 {
+                let mut request = ResourceRequest::new();
+                request.read::<Tag>();
+                dispatcher.with(Box::new(Tagger { request : request, entity : entity.clone(), tag : tag }));
+            }
+            dispatcher.on_update(resources.clone());
+
+            // the systems share a stage, the last system added still wins
+            let entity = entity.lock().unwrap().unwrap();
+            assert!(tagged(&resources, entity) == Some(4));
+        }
+    }
+
+    #[test]
+    fn test_structural_changes() {
+        let resources = Resources::new();
+        let kept;
+        let dropped;
+        {
+            let token = resources.get_token();
+            let mut commands = token.commands();
+            kept = commands.spawn();
+            dropped = commands.spawn();
+            commands.insert(kept, Tag(1))
+                .insert(dropped, Tag(2))
+                .despawn(dropped)
+                .insert(dropped, Tag(3));
+            assert!(commands.len() == 4);
+        }
+        // nothing happens until the commands are applied
+        assert!(!resources.contains::<Tag>());
+        resources.apply_commands();
+        assert!(tagged(&resources, kept) == Some(1));
+        assert!(!resources.is_alive(&dropped));
+        assert!(tagged(&resources, dropped).is_none());
+
+        resources.get_token().commands().remove::<Tag>(kept);
+        resources.apply_commands();
+        assert!(tagged(&resources, kept).is_none());
+    }
+}
@@ -1,14 +1,34 @@
-use systems::System;
+use systems::{System, SystemOrder};
 use state::Trans;
-use resources::Resources;
+use resources::{Resources, ResourceToken};
 use std::sync::Arc;
+use std::{fmt,error};
+use std::error::Error;
+use std::fmt::Display;
 use rayon::prelude::*;
 
+/// How a Dispatcher runs its systems
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DispatchMode {
+    /// Systems are run in stages on rayon's thread pool
+    Parallel,
+    /// Systems are run one at a time in order on the calling thread,
+    /// which makes every run deterministic
+    Sequential,
+}
+
 /// Responsible for deciding when systems get to
-/// run, this simple Dispatcher executes systems 
-/// in Fifo order
+/// run, systems are grouped into stages of systems
+/// whose requests don't conflict, each stage is run
+/// in parallel and the stages are run in Fifo order.
+/// Systems can also be explicitly ordered with labels
 pub struct Dispatcher {
     systems : Vec<Box<System>>,
+    orders : Vec<SystemOrder>,
+    dependencies : Vec<Vec<usize>>,
+    last_runs : Vec<u64>,
+    built : bool,
+    mode : DispatchMode,
 }
 
 impl Dispatcher {
@@ -16,55 +36,593 @@ impl Dispatcher {
     pub fn new() -> Dispatcher {
         Dispatcher {
             systems : Vec::new(),
+            orders : Vec::new(),
+            dependencies : Vec::new(),
+            last_runs : Vec::new(),
+            built : true,
+            mode : DispatchMode::Parallel,
         }
     }
 
+    /// Sets how the dispatcher runs its systems
+    pub fn set_mode(&mut self, mode : DispatchMode) -> &Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Returns how the dispatcher runs its systems
+    pub fn mode(&self) -> DispatchMode {
+        self.mode
+    }
+
     /// Adds a system to a dispatcher
     pub fn with(&mut self, system : Box<System>) -> &Self{
+        self.with_ordered(system, SystemOrder::new())
+    }
+
+    /// Adds a system to a dispatcher that is ordered against
+    /// other systems, the order is resolved once the dispatcher is built
+    pub fn with_ordered(&mut self, system : Box<System>, order : SystemOrder) -> &Self{
         self.systems.push(system);
+        self.orders.push(order);
+        self.dependencies.push(Vec::new());
+        self.last_runs.push(0);
+        self.built = false;
         self
     }
 
+    /// Returns a readable name for the system at the index
+    fn name(&self, index : usize) -> String {
+        match self.orders[index].name {
+            Some(ref name) => name.clone(),
+            None => format!("system #{}", index),
+        }
+    }
+
+    /// Returns the systems that have the label
+    fn labeled(&self, label : &str) -> Result<Vec<usize>, OrderError> {
+        let labeled : Vec<usize> = (0..self.orders.len())
+            .filter(|index| self.orders[*index].has_label(label))
+            .collect();
+        match labeled.len() {
+            0 => Err(OrderError::UnknownLabel(label.to_string())),
+            _ => Ok(labeled),
+        }
+    }
+
+    /// Topologically sorts the systems so every system runs after the
+    /// systems it is ordered after. Systems without constraints between
+    /// them keep the order they were added in. Returns an error if the
+    /// constraints form a cycle or refer to a label no system has
+    pub fn build(&mut self) -> Result<(), OrderError> {
+        let count = self.systems.len();
+
+        // Collect the systems each system has to wait for
+        let mut waits_on : Vec<Vec<usize>> = vec![Vec::new(); count];
+        for index in 0..count {
+            for label in self.orders[index].after.iter() {
+                waits_on[index].extend(self.labeled(label)?);
+            }
+            for label in self.orders[index].before.iter() {
+                for later in self.labeled(label)? {
+                    waits_on[later].push(index);
+                }
+            }
+        }
+        for (index, waits) in waits_on.iter_mut().enumerate() {
+            waits.retain(|earlier| *earlier != index);
+            waits.sort();
+            waits.dedup();
+        }
+
+        // Repeatedly take the first system whose dependencies are all sorted
+        let mut sorted : Vec<usize> = Vec::with_capacity(count);
+        let mut placed = vec![false; count];
+        while sorted.len() < count {
+            let next = (0..count).find(|index| {
+                !placed[*index] && waits_on[*index].iter().all(|earlier| placed[*earlier])
+            });
+            match next {
+                Some(index) => {
+                    placed[index] = true;
+                    sorted.push(index);
+                }
+                None => {
+                    let cycle = (0..count).filter(|index| !placed[*index])
+                        .map(|index| self.name(index))
+                        .collect();
+                    return Err(OrderError::Cycle(cycle));
+                }
+            }
+        }
+
+        // Reorder the systems and translate the dependencies to the new positions
+        let mut position = vec![0; count];
+        for (new, old) in sorted.iter().enumerate() {
+            position[*old] = new;
+        }
+        let mut systems : Vec<Option<Box<System>>> = self.systems.drain(..).map(Some).collect();
+        let mut orders : Vec<Option<SystemOrder>> = self.orders.drain(..).map(Some).collect();
+        for old in sorted.iter() {
+            self.systems.push(systems[*old].take().unwrap());
+            self.orders.push(orders[*old].take().unwrap());
+        }
+        self.last_runs = sorted.iter().map(|old| self.last_runs[*old]).collect();
+        self.dependencies = sorted.iter()
+            .map(|old| waits_on[*old].iter().map(|earlier| position[*earlier]).collect())
+            .collect();
+        self.built = true;
+        Ok(())
+    }
+
+    /// Builds the dispatcher if systems were added since it was last built
+    fn ensure_built(&mut self) {
+        if !self.built {
+            if let Err(error) = self.build() {
+                panic!("{}", error);
+            }
+        }
+    }
+
+    /// Assigns every system to a stage. A system is placed in the stage after
+    /// the last earlier system it conflicts with or is ordered after, so
+    /// conflicting systems run in the order they were added. Systems that
+    /// don't declare their resources conflict with every other system
+    fn stages(&self) -> Vec<usize> {
+        let mut stages : Vec<usize> = Vec::with_capacity(self.systems.len());
+        for (index, system) in self.systems.iter().enumerate() {
+            let mut stage = 0;
+            for earlier in 0..index {
+                let conflicts = match (system.resources(), self.systems[earlier].resources()) {
+                    (Some(request), Some(other)) => request.conflicts(other),
+                    _ => true,
+                };
+                if conflicts || self.dependencies[index].contains(&earlier) {
+                    stage = stage.max(stages[earlier] + 1);
+                }
+            }
+            stages.push(stage);
+        }
+        stages
+    }
+
+    /// Runs the function for every system with a token that reports every change,
+    /// in the same order and stages as an update, then applies the commands the
+    /// systems queued
+    fn for_each<F>(&mut self, resources : &Resources, function : F)
+        where F : Fn(&mut Box<System>, ResourceToken) + Send + Sync {
+        self.ensure_built();
+        self.dispatch(|index, system, _| {
+            function(system, resources.system_token(index, 0));
+            Trans::None
+        });
+        resources.apply_commands();
+    }
+
     /// This will run the on_update function for all the systems that
-    /// the dispatcher overlooks
+    /// the dispatcher overlooks. If multiple systems return a transition
+    /// the one from the system added first is used. Each system is handed
+    /// a token that reports the changes made since the system last ran.
+    /// Once every system has returned the queued commands are applied
+    /// in the order the systems were added
     pub fn on_update(&mut self, resources : Arc<Resources>) -> Trans {
-        self.systems.par_iter_mut().map(|system| {
-            system.update(resources.get_token())
-        }).reduce(|| Trans::None, |a,b| match a {
-            Trans::None => b,
-            _ => a,
+        self.ensure_built();
+        let transition = self.update(&resources);
+        resources.apply_commands();
+        transition
+    }
+
+    /// Returns the tick of the system that ran the longest time ago, every
+    /// system has seen the removals made at or before it
+    pub fn oldest_run(&self) -> Option<u64> {
+        self.last_runs.iter().min().cloned()
+    }
+
+    /// Treats every system as having last run at the tick, so the
+    /// systems only see the changes made after it
+    pub fn set_last_runs(&mut self, tick : u64) {
+        for last_run in self.last_runs.iter_mut() {
+            *last_run = tick;
+        }
+    }
+
+    /// Updates the systems and returns the first transition
+    fn update(&mut self, resources : &Resources) -> Trans {
+        self.dispatch(|index, system, last_run| {
+            let trans = system.update(resources.system_token(index, *last_run));
+            *last_run = resources.tick();
+            trans
         })
     }
 
+    /// Runs the function for every system along with its index and last run, in
+    /// order or stage by stage depending on the mode of the dispatcher. Returns the
+    /// transition of the earliest system that returned one
+    fn dispatch<F>(&mut self, function : F) -> Trans
+        where F : Fn(usize, &mut Box<System>, &mut u64) -> Trans + Send + Sync {
+        if self.mode == DispatchMode::Sequential {
+            let mut transition = Trans::None;
+            for (index, (system, last_run)) in self.systems.iter_mut().zip(self.last_runs.iter_mut()).enumerate() {
+                let trans = function(index, system, last_run);
+                if let Trans::None = transition {
+                    transition = trans;
+                }
+            }
+            return transition;
+        }
+        let stages = self.stages();
+        let count = stages.iter().max().map_or(0, |last| last + 1);
+        let mut transitions : Vec<(usize, Trans)> = Vec::new();
+        for stage in 0..count {
+            let mut results : Vec<(usize, Trans)> = self.systems.par_iter_mut()
+                .zip(self.last_runs.par_iter_mut())
+                .enumerate()
+                .filter(|(index, _)| stages[*index] == stage)
+                .map(|(index, (system, last_run))| (index, function(index, system, last_run)))
+                .filter(|(_, trans)| match trans {
+                    Trans::None => false,
+                    _ => true,
+                })
+                .collect();
+            transitions.append(&mut results);
+        }
+        transitions.sort_by_key(|(index, _)| *index);
+        match transitions.into_iter().next() {
+            Some((_, trans)) => trans,
+            None => Trans::None,
+        }
+    }
+
     /// This will run the on_start function for all the systems that
     /// the dispatcher overlooks
     pub fn on_start(&mut self, resources : Arc<Resources>) {
-        self.systems.par_iter_mut().for_each(|system| {
-            system.start(resources.get_token());
+        self.for_each(&resources, |system, token| {
+            system.start(token);
         });
     }
 
     /// This will run the on_exit function for all the systems that
     /// the dispatcher overlooks
     pub fn on_exit(&mut self, resources : Arc<Resources>) {
-        self.systems.par_iter_mut().for_each(|system| {
-            system.exit(resources.get_token());
+        self.for_each(&resources, |system, token| {
+            system.exit(token);
         });
     }
 
     /// This will run the on_pause function for all the systems that
     /// the dispatcher overlooks
     pub fn on_pause(&mut self, resources : Arc<Resources>) {
-        self.systems.par_iter_mut().for_each(|system| {
-            system.pause(resources.get_token());
+        self.for_each(&resources, |system, token| {
+            system.pause(token);
         });
     }
 
     /// This will run the on_resume function for all the systems that
     /// the dispatcher overlooks
     pub fn on_resume(&mut self, resources : Arc<Resources>) {
-        self.systems.par_iter_mut().for_each(|system| {
-            system.resume(resources.get_token());
+        self.for_each(&resources, |system, token| {
+            system.resume(token);
         });
     }
-}
\ No newline at end of file
+}
+
+/*************************************************/
+/* Error returned when systems can't be ordered  */
+/*************************************************/
+#[derive(Debug)]
+pub enum OrderError {
+    /// The names of the systems whose constraints form a cycle
+    Cycle(Vec<String>),
+    /// A constraint refers to a label that no system has
+    UnknownLabel(String),
+}
+impl Display for OrderError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OrderError::Cycle(systems) => write!(f, "Systems are ordered in a cycle: {}", systems.join(", ")),
+            OrderError::UnknownLabel(label) => write!(f, "No system has the label {}", label),
+        }
+    }
+}
+impl Error for OrderError {
+    fn cause(&self) -> Option<&error::Error> {
+        None
+    }
+}
+
+/*************************************************/
+/* Unit Tests                                    */
+/*************************************************/
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use resources::{Component, ComponentVector, ResourceRequest, ResourceToken};
+    use query::{Added, Changed};
+    use entity::Entity;
+    use std::sync::Mutex;
+    use std::thread::{self, ThreadId};
+
+    struct CompA;
+    struct CompB;
+    impl Component for CompA {
+        type Storage = ComponentVector<Self>;
+    }
+    impl Component for CompB {
+        type Storage = ComponentVector<Self>;
+    }
+
+    struct Declared {
+        request : ResourceRequest,
+    }
+    impl System for Declared {
+        fn resources(&self) -> Option<&ResourceRequest> {
+            Some(&self.request)
+        }
+    }
+
+    struct Undeclared;
+    impl System for Undeclared {}
+
+    struct Transition(u32);
+    impl System for Transition {
+        fn update(&mut self, _res : ResourceToken) -> Trans {
+            match self.0 {
+                0 => Trans::None,
+                1 => Trans::Pop,
+                _ => Trans::Swap(::state::State::new()),
+            }
+        }
+    }
+
+    /// Spawns an entity with CompA when started, without registering CompA
+    struct Starter {
+        request : ResourceRequest,
+    }
+    impl System for Starter {
+        fn start(&mut self, token : ResourceToken) {
+            let token = token.request(&self.request).unwrap();
+            token.create_entity().with(CompA).build().unwrap();
+        }
+    }
+
+    fn declared(build : fn(&mut ResourceRequest)) -> Box<System> {
+        let mut request = ResourceRequest::new();
+        build(&mut request);
+        Box::new(Declared { request : request })
+    }
+
+    #[test]
+    fn test_lazy_registration() {
+        let resources = Arc::new(Resources::new());
+        let mut dispatcher = Dispatcher::new();
+        for _ in 0..4 {
+            let mut request = ResourceRequest::new();
+            request.write::<CompA>();
+            dispatcher.with(Box::new(Starter { request : request }));
+        }
+        dispatcher.on_start(resources.clone());
+
+        let mut request = ResourceRequest::new();
+        request.read::<CompA>();
+        let token = resources.get_token().request(&request).unwrap();
+        assert!(token.unpack::<CompA>().unwrap().iter().count() == 4);
+    }
+
+    #[test]
+    fn test_stages() {
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.with(declared(|r| { r.read::<CompA>(); }));
+        dispatcher.with(declared(|r| { r.read::<CompA>().write::<CompB>(); }));
+        dispatcher.with(declared(|r| { r.write::<CompA>(); }));
+        dispatcher.with(declared(|r| { r.read::<CompB>(); }));
+        dispatcher.with(Box::new(Undeclared));
+        dispatcher.with(declared(|r| { r.read::<CompA>(); }));
+
+        // readers share a stage, writers wait for every earlier user of
+        // the key and undeclared systems get a stage of their own
+        assert!(dispatcher.stages() == vec!(0, 0, 1, 1, 2, 3));
+    }
+
+    struct Record {
+        name : &'static str,
+        log : Arc<Mutex<Vec<&'static str>>>,
+    }
+    impl System for Record {
+        fn update(&mut self, _res : ResourceToken) -> Trans {
+            self.log.lock().unwrap().push(self.name);
+            Trans::None
+        }
+        fn start(&mut self, _res : ResourceToken) { self.log.lock().unwrap().push(self.name); }
+        fn exit(&mut self, _res : ResourceToken) { self.log.lock().unwrap().push(self.name); }
+        fn pause(&mut self, _res : ResourceToken) { self.log.lock().unwrap().push(self.name); }
+        fn resume(&mut self, _res : ResourceToken) { self.log.lock().unwrap().push(self.name); }
+    }
+
+    #[test]
+    fn test_ordering() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let record = |name| Box::new(Record { name : name, log : log.clone() });
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.with_ordered(record("render"), SystemOrder::named("render").after("simulation"));
+        dispatcher.with_ordered(record("physics"), SystemOrder::named("physics").label("simulation").after("input"));
+        dispatcher.with(record("audio"));
+        dispatcher.with_ordered(record("ai"), SystemOrder::named("ai").label("simulation"));
+        dispatcher.with_ordered(record("input"), SystemOrder::named("input").before("ai"));
+        dispatcher.build().unwrap();
+        dispatcher.on_update(Arc::new(Resources::new()));
+        assert!(*log.lock().unwrap() == vec!("audio", "input", "physics", "ai", "render"));
+    }
+
+    #[test]
+    fn test_lifecycle_ordering() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let record = |name| Box::new(Record { name : name, log : log.clone() });
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.with_ordered(record("render"), SystemOrder::named("render").after("simulation"));
+        dispatcher.with_ordered(record("physics"), SystemOrder::named("physics").label("simulation"));
+        dispatcher.with(record("audio"));
+
+        // every lifecycle hook runs the systems in the order of an update
+        let resources = Arc::new(Resources::new());
+        dispatcher.on_start(resources.clone());
+        dispatcher.on_pause(resources.clone());
+        dispatcher.on_resume(resources.clone());
+        dispatcher.on_exit(resources.clone());
+        let log = log.lock().unwrap();
+        for hook in log.chunks(3) {
+            assert!(hook == ["physics", "render", "audio"]);
+        }
+        assert!(log.len() == 12);
+    }
+
+    #[test]
+    fn test_ordering_stages() {
+        // Systems that don't conflict are still split into stages when ordered
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.with_ordered(declared(|r| { r.read::<CompA>(); }), SystemOrder::named("second").after("first"));
+        dispatcher.with_ordered(declared(|r| { r.read::<CompA>(); }), SystemOrder::named("first"));
+        dispatcher.with(declared(|r| { r.read::<CompA>(); }));
+        dispatcher.build().unwrap();
+        assert!(dispatcher.stages() == vec!(0, 1, 0));
+    }
+
+    #[test]
+    fn test_ordering_errors() {
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.with_ordered(Box::new(Undeclared), SystemOrder::named("a").after("b"));
+        dispatcher.with_ordered(Box::new(Undeclared), SystemOrder::named("b").after("c"));
+        dispatcher.with_ordered(Box::new(Undeclared), SystemOrder::named("c").after("a"));
+        dispatcher.with(Box::new(Undeclared));
+        match dispatcher.build() {
+            Err(OrderError::Cycle(systems)) => assert!(systems == vec!("a", "b", "c")),
+            _ => panic!("the cycle should be reported"),
+        }
+
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.with_ordered(Box::new(Undeclared), SystemOrder::new().before("missing"));
+        match dispatcher.build() {
+            Err(OrderError::UnknownLabel(label)) => assert!(label == "missing"),
+            _ => panic!("the unknown label should be reported"),
+        }
+    }
+
+    /// Logs its name along with the thread it was run on
+    struct Threaded {
+        name : usize,
+        request : ResourceRequest,
+        log : Arc<Mutex<Vec<(usize, ThreadId)>>>,
+    }
+    impl System for Threaded {
+        fn resources(&self) -> Option<&ResourceRequest> {
+            Some(&self.request)
+        }
+
+        fn update(&mut self, _res : ResourceToken) -> Trans {
+            self.log.lock().unwrap().push((self.name, thread::current().id()));
+            Trans::None
+        }
+    }
+
+    #[test]
+    fn test_sequential() {
+        // The systems don't conflict so they would share a stage in parallel mode
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.set_mode(DispatchMode::Sequential);
+        for name in 0..16 {
+            dispatcher.with(Box::new(Threaded { name : name, request : ResourceRequest::new(), log : log.clone() }));
+        }
+        let resources = Arc::new(Resources::new());
+        for _ in 0..4 {
+            dispatcher.on_update(resources.clone());
+        }
+        let expected : Vec<(usize, ThreadId)> = (0..4).flat_map(|_| 0..16)
+            .map(|name| (name, thread::current().id()))
+            .collect();
+        assert!(*log.lock().unwrap() == expected);
+    }
+
+    #[test]
+    fn test_first_transition() {
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.with(Box::new(Transition(0)));
+        dispatcher.with(Box::new(Transition(1)));
+        dispatcher.with(Box::new(Transition(2)));
+        match dispatcher.on_update(Arc::new(Resources::new())) {
+            Trans::Pop => (),
+            _ => panic!("the transition of the earliest system should be used"),
+        }
+    }
+
+    struct Counter(u64);
+    impl Component for Counter {
+        type Storage = ComponentVector<Self>;
+    }
+
+    /// Spawns, changes and deletes counters on successive frames
+    struct Spawner {
+        frame : u64,
+        request : ResourceRequest,
+        spawned : Vec<Entity>,
+    }
+    impl System for Spawner {
+        fn resources(&self) -> Option<&ResourceRequest> {
+            Some(&self.request)
+        }
+
+        fn update(&mut self, token : ResourceToken) -> Trans {
+            self.frame += 1;
+            let token = token.request(&self.request).unwrap();
+            match self.frame {
+                1 => for id in 0..2 {
+                    let entity = token.register_entity().with(Counter(id), token.unpack_mut::<Counter>().unwrap());
+                    self.spawned.push(entity);
+                },
+                2 => token.get_mut::<Counter>(self.spawned[1]).unwrap().0 += 1,
+                3 => { token.delete_entity(self.spawned[0]); },
+                _ => (),
+            }
+            Trans::None
+        }
+    }
+
+    /// Logs how many counters were added, changed and removed since it last ran
+    struct Watcher {
+        request : ResourceRequest,
+        log : Arc<Mutex<Vec<(usize, usize, usize)>>>,
+    }
+    impl System for Watcher {
+        fn resources(&self) -> Option<&ResourceRequest> {
+            Some(&self.request)
+        }
+
+        fn update(&mut self, token : ResourceToken) -> Trans {
+            let token = token.request(&self.request).unwrap();
+            let added = token.join::<(Added<Counter>,)>().unwrap().iter_mut().count();
+            let changed = token.join::<(Changed<Counter>,)>().unwrap().iter_mut().count();
+            let removed = token.removed::<Counter>().len();
+            self.log.lock().unwrap().push((added, changed, removed));
+            Trans::None
+        }
+    }
+
+    #[test]
+    fn test_change_detection() {
+        let resources = Arc::new(Resources::new());
+        resources.register::<Counter>();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut writer = ResourceRequest::new();
+        writer.write::<Counter>();
+        let mut reader = ResourceRequest::new();
+        reader.read::<Counter>();
+
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.with(Box::new(Spawner { frame : 0, request : writer, spawned : Vec::new() }));
+        dispatcher.with(Box::new(Watcher { request : reader, log : log.clone() }));
+        for _ in 0..4 {
+            dispatcher.on_update(resources.clone());
+        }
+        // the deletion is applied at the end of the third frame
+        assert!(*log.lock().unwrap() == vec!((2, 2, 0), (0, 1, 0), (0, 0, 0), (0, 0, 1)));
+    }
+}
@@ -1,38 +1,106 @@
 use std::ops::Range;
 use std::cell::RefMut;
-use resources::{Component,ComponentVector};
+use bit_field::BitField;
+use resources::{Component, ResourceToken, UnpackError};
+use storage::Storage;
 
-/// The EntityRegister generates id's for 
-/// Entities so that entities have unique names
+pub(crate) const INDEX_BITS : Range<usize> = 0..36;
+pub(crate) const GENERATION_BITS : Range<usize> = 36..64;
+
+/// Returns the index portion of an Entity id
+pub(crate) fn index_of(id : u64) -> u64 {
+    id.get_bits(INDEX_BITS)
+}
+
+/// Returns the generation portion of an Entity id
+pub(crate) fn generation_of(id : u64) -> u64 {
+    id.get_bits(GENERATION_BITS)
+}
+
+/// The EntityRegister generates id's for
+/// Entities so that entities have unique names.
+/// An id is made up of an index and a generation, once an
+/// Entity is killed its index is recycled with the next generation
 pub struct EntityRegister {
     pub(crate) entity : u64,
+    generations : Vec<u64>,
+    alive : Vec<bool>,
+    free : Vec<u64>,
 }
 
 impl EntityRegister {
     /// Creates a new EntityRegister
     pub fn new() -> EntityRegister {
         EntityRegister {
-            entity : 0
+            entity : 0,
+            generations : Vec::new(),
+            alive : Vec::new(),
+            free : Vec::new(),
         }
     }
 
     /// Registers new Entity id's, multiple id's can be registered
-    /// at one time.
+    /// at one time. The id's are always fresh indices in their first
+    /// generation, recycled indices are only handed out by allocate
     pub fn register(&mut self, number_to_register : u64) -> Range<u64> {
         let start = self.entity;
+        assert!(start + number_to_register <= 1 << INDEX_BITS.end,
+            "EntityRegister has run out of entity indices");
         self.entity += number_to_register;
+        self.generations.resize(self.entity as usize, 0);
+        self.alive.resize(self.entity as usize, true);
         start..self.entity
     }
 
+    /// Allocates a single Entity, reusing the index of a killed
+    /// Entity if one is available
+    pub fn allocate(&mut self) -> Entity {
+        match self.free.pop() {
+            Some(index) => {
+                self.alive[index as usize] = true;
+                Entity::new(index, self.generations[index as usize])
+            }
+            None => Entity::new_with_id(self.register(1).start),
+        }
+    }
+
+    /// Marks the id as dead so its index can be recycled, returns false
+    /// if the id was never registered or was already dead
+    pub fn kill(&mut self, id : u64) -> bool {
+        if !self.is_alive(id) {
+            return false;
+        }
+        let index = index_of(id) as usize;
+        self.alive[index] = false;
+        self.generations[index] = (self.generations[index] + 1) % (1 << (GENERATION_BITS.end - GENERATION_BITS.start));
+        self.free.push(index as u64);
+        true
+    }
+
+    /// Returns true if the id has been registered and not killed
+    pub fn is_alive(&self, id : u64) -> bool {
+        let index = index_of(id) as usize;
+        index < self.alive.len() && self.alive[index] && self.generations[index] == generation_of(id)
+    }
+
 }
 
 /// An entity contains an ID and can be used to add
 /// components to a Resource under it's id
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct Entity{
     pub(crate) id :  u64,
 }
 
 impl Entity {
+    /// Creates a new Entity from an index and a generation
+    pub(crate) fn new(index : u64, generation : u64) -> Entity {
+        Entity {
+            id: *0.set_bits(INDEX_BITS, index)
+                  .set_bits(GENERATION_BITS, generation),
+        }
+    }
+
     /// Creates a new Entity given an Entity id, this does not consult the
     /// The register so use high values between 2^32..2^36 or use values
     /// previously registered with the Entity Register but not yet entered
@@ -43,14 +111,208 @@ impl Entity {
         }
     }
 
-    /// Returns the id of the Entity
+    /// Returns the id of the Entity, which includes its generation
     pub fn id(&self) -> u64 {
         self.id
     }
 
+    /// Returns the index of the Entity, indices are recycled once
+    /// an Entity is deleted
+    pub fn index(&self) -> u64 {
+        index_of(self.id)
+    }
+
+    /// Returns how many times the index of the Entity has been recycled
+    pub fn generation(&self) -> u64 {
+        generation_of(self.id)
+    }
+
     /// Add's a component to the resources under this entity
-    pub fn with<T>(self, comp : T, mut write : RefMut<&mut Box<ComponentVector<T>>>) -> Self where T : Component {
-        write.push(comp,self.id);
+    pub fn with<T>(self, comp : T, mut write : RefMut<T::Storage>) -> Self where T : Component {
+        write.insert(self.id, comp);
+        self
+    }
+}
+
+/*************************************************/
+/* Builds an Entity from the loan of a token     */
+/*************************************************/
+/// Collects the components of a new Entity, the collections are looked up
+/// from the loan of the token once the entity is built. Nothing is inserted
+/// and no Entity is allocated unless every component can be written
+pub struct EntityBuilder<'t, 'a : 't> {
+    token : &'t ResourceToken<'a>,
+    checks : Vec<Box<Fn(&ResourceToken<'a>) -> Result<(), UnpackError> + 't>>,
+    inserts : Vec<Box<FnOnce(&ResourceToken<'a>, Entity) + 't>>,
+}
+
+impl<'t, 'a : 't> EntityBuilder<'t, 'a> {
+    pub(crate) fn new(token : &'t ResourceToken<'a>) -> EntityBuilder<'t, 'a> {
+        EntityBuilder {
+            token : token,
+            checks : Vec::new(),
+            inserts : Vec::new(),
+        }
+    }
+
+    /// Adds a component to the Entity, the Component must be loaned with write permisions
+    pub fn with<C : Component>(mut self, component : C) -> Self {
+        self.checks.push(Box::new(|token| token.unpack_mut::<C>().map(|_| ())));
+        self.inserts.push(Box::new(move |token, entity| {
+            token.unpack_mut::<C>().unwrap().insert(entity.id(), component);
+        }));
         self
     }
-}
\ No newline at end of file
+
+    /// Allocates the Entity and inserts its components, returns the error of the
+    /// first component whose collection can't be written to through the loan
+    pub fn build(self) -> Result<Entity, UnpackError> {
+        for check in self.checks.iter() {
+            check(self.token)?;
+        }
+        let entity = self.token.register_entity();
+        for insert in self.inserts {
+            insert(self.token, entity);
+        }
+        Ok(entity)
+    }
+}
+
+/*************************************************/
+/* A tuple of Components spawned together        */
+/*************************************************/
+pub trait Bundle : Sized {
+    /// Returns an error if one of the collections can't be written to through the loan
+    fn check(token : &ResourceToken) -> Result<(), UnpackError>;
+
+    /// Inserts the components of every bundle under the matching id
+    fn insert(token : &ResourceToken, ids : Range<u64>, bundles : Vec<Self>);
+}
+
+macro_rules! impl_bundle {
+    ($($comp:ident $value:ident),+) => {
+        #[allow(non_snake_case)]
+        impl<$($comp : Component),+> Bundle for ($($comp,)+) {
+            fn check(token : &ResourceToken) -> Result<(), UnpackError> {
+                $(token.unpack_mut::<$comp>()?;)+
+                Ok(())
+            }
+
+            fn insert(token : &ResourceToken, ids : Range<u64>, bundles : Vec<Self>) {
+                // split the bundles into a column per Component
+                $(let mut $comp : Vec<$comp> = Vec::with_capacity(bundles.len());)+
+                for ($($value,)+) in bundles {
+                    $($comp.push($value);)+
+                }
+                $(
+                    let mut storage = token.unpack_mut::<$comp>().unwrap();
+                    storage.reserve($comp.len());
+                    for (id, component) in ids.clone().zip($comp) {
+                        storage.insert(id, component);
+                    }
+                )+
+            }
+        }
+    };
+}
+
+impl_bundle!(A a);
+impl_bundle!(A a, B b);
+impl_bundle!(A a, B b, C c);
+impl_bundle!(A a, B b, C c, D d);
+impl_bundle!(A a, B b, C c, D d, E e);
+impl_bundle!(A a, B b, C c, D d, E e, F f);
+impl_bundle!(A a, B b, C c, D d, E e, F f, G g);
+impl_bundle!(A a, B b, C c, D d, E e, F f, G g, H h);
+
+/*************************************************/
+/* Unit Tests                                    */
+/*************************************************/
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recycle() {
+        let mut register = EntityRegister::new();
+        let first = register.allocate();
+        let second = register.allocate();
+        assert!(first.index() == 0 && second.index() == 1);
+        assert!(register.is_alive(first.id()));
+
+        assert!(register.kill(first.id()));
+        assert!(!register.kill(first.id()));
+        assert!(!register.is_alive(first.id()));
+
+        // the index is reused with the next generation
+        let third = register.allocate();
+        assert!(third.index() == first.index());
+        assert!(third.generation() == first.generation() + 1);
+        assert!(register.is_alive(third.id()));
+        assert!(!register.is_alive(first.id()));
+
+        // ranges are always fresh indices
+        assert!(register.register(3) == (2..5));
+        assert!(register.is_alive(4));
+        assert!(!register.is_alive(5));
+    }
+
+    use resources::{Resources, ResourceRequest, ComponentVector};
+
+    struct Pos(u32);
+    impl Component for Pos {
+        type Storage = ComponentVector<Self>;
+    }
+    struct Vel(u32);
+    impl Component for Vel {
+        type Storage = ComponentVector<Self>;
+    }
+
+    #[test]
+    fn test_builder() {
+        let resources = Resources::new();
+        resources.register::<Pos>();
+        resources.register::<Vel>();
+
+        let mut request = ResourceRequest::new();
+        request.write::<Pos>().read::<Vel>();
+        let token = resources.get_token().request(&request).unwrap();
+        let entity = token.create_entity().with(Pos(1)).build().unwrap();
+        assert!(token.get_mut::<Pos>(entity).map(|pos| pos.0) == Some(1));
+
+        // Vel is only readable so nothing is built
+        match token.create_entity().with(Pos(2)).with(Vel(3)).build() {
+            Err(UnpackError::ReadOnly(name)) => assert!(name.ends_with("Vel")),
+            _ => panic!("Vel was only loaned for reading"),
+        }
+        assert!(token.unpack_mut::<Pos>().unwrap().iter().count() == 1);
+        assert!(token.register_entity().index() == entity.index() + 1);
+    }
+
+    #[test]
+    fn test_spawn_batch() {
+        let resources = Resources::new();
+        resources.register::<Pos>();
+        resources.register::<Vel>();
+        let first = resources.get_token().register_entity();
+
+        let mut request = ResourceRequest::new();
+        request.write::<Pos>().write::<Vel>();
+        let token = resources.get_token().request(&request).unwrap();
+        let entities = token.spawn_batch((0..10000).map(|i| (Pos(i), Vel(i * 2)))).unwrap();
+        assert!(entities.len() == 10000);
+        assert!(entities[0].index() == first.index() + 1);
+        for (i, entity) in entities.iter().enumerate() {
+            assert!(token.get_mut::<Vel>(*entity).map(|vel| vel.0) == Some(i as u32 * 2));
+        }
+        let positions : Vec<u32> = token.unpack_mut::<Pos>().unwrap().iter().map(|(_, pos)| pos.0).collect();
+        assert!(positions == (0..10000).collect::<Vec<u32>>());
+
+        // without write access nothing is spawned
+        let mut request = ResourceRequest::new();
+        request.write::<Pos>().read::<Vel>();
+        let token = token.request(&request).unwrap();
+        assert!(token.spawn_batch(vec!((Pos(0), Vel(0)))).is_err());
+        assert!(token.register_entity().index() == entities[9999].index() + 1);
+    }
+}
@@ -0,0 +1,300 @@
+use std::any::Any;
+use std::collections::{VecDeque, vec_deque};
+use std::marker::PhantomData;
+use std::sync::{Arc, Weak, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use resources::ComponentCollection;
+use storage::Changes;
+
+/*************************************************/
+/* A queue of events that systems communicate by */
+/*************************************************/
+/// Events are written through a write loan and read through a read loan,
+/// every reader keeps its own cursor so each reader sees every event once.
+/// Events expire once every reader has seen them, or once they are older
+/// than the lifetime of the channel
+pub struct EventChannel<E : Send + Sync + 'static> {
+    // the sequence number and frame of every event
+    events : VecDeque<(u64, u64, E)>,
+    next : u64,
+    frame : u64,
+    lifetime : Option<u64>,
+    readers : Mutex<Vec<Weak<AtomicU64>>>,
+    changes : Changes,
+}
+
+impl<E : Send + Sync + 'static> EventChannel<E> {
+    /// Constructs a channel that keeps events until every reader has seen them
+    pub fn new() -> EventChannel<E> {
+        EventChannel {
+            events : VecDeque::new(),
+            next : 0,
+            frame : 0,
+            lifetime : None,
+            readers : Mutex::new(Vec::new()),
+            changes : Changes::new(),
+        }
+    }
+
+    /// Constructs a channel whose events are also dropped once they have
+    /// been kept for the number of frames after the frame they were written in
+    pub fn with_lifetime(frames : u64) -> EventChannel<E> {
+        let mut channel = EventChannel::new();
+        channel.lifetime = Some(frames);
+        channel
+    }
+
+    /// Registers a new reader, the reader only sees events written after it was
+    /// registered. Dropping the ReaderId unregisters the reader
+    pub fn register_reader(&self) -> ReaderId<E> {
+        let cursor = Arc::new(AtomicU64::new(self.next));
+        self.readers.lock().unwrap().push(Arc::downgrade(&cursor));
+        ReaderId {
+            cursor : cursor,
+            marker : PhantomData,
+        }
+    }
+
+    /// Adds an event to the channel
+    pub fn single_write(&mut self, event : E) {
+        self.events.push_back((self.next, self.frame, event));
+        self.next += 1;
+    }
+
+    /// Adds every event of the iterator to the channel
+    pub fn iter_write<I : IntoIterator<Item = E>>(&mut self, events : I) {
+        for event in events {
+            self.single_write(event);
+        }
+    }
+
+    /// Returns the events the reader has not seen yet, the readers cursor
+    /// moves past every event as it is yielded so events that are not
+    /// iterated over are returned by the next read
+    pub fn read<'a>(&'a self, reader : &'a mut ReaderId<E>) -> EventIter<'a, E> {
+        let cursor = reader.cursor.load(Ordering::SeqCst);
+        // the sequence numbers in the queue are contiguous
+        let first = self.events.front().map_or(self.next, |(sequence, _, _)| *sequence);
+        let start = (cursor.saturating_sub(first) as usize).min(self.events.len());
+        EventIter {
+            events : self.events.range(start..),
+            cursor : &reader.cursor,
+        }
+    }
+
+    /// Returns the number of events stored in the channel
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    /// Returns true if the channel holds no events
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Ends the frame, dropping the events every reader has seen
+    /// along with the events that outlived the channels lifetime
+    pub fn maintain(&mut self) {
+        self.frame += 1;
+        let seen = {
+            let mut readers = self.readers.lock().unwrap();
+            readers.retain(|reader| reader.upgrade().is_some());
+            readers.iter()
+                .filter_map(|reader| reader.upgrade())
+                .map(|cursor| cursor.load(Ordering::SeqCst))
+                .min()
+                .unwrap_or(self.next)
+        };
+        let frame = self.frame;
+        let lifetime = self.lifetime;
+        while let Some(&(sequence, written, _)) = self.events.front() {
+            let expired = lifetime.map_or(false, |lifetime| frame - written > lifetime);
+            if sequence >= seen && !expired {
+                break;
+            }
+            self.events.pop_front();
+        }
+    }
+}
+
+impl<E : Send + Sync + 'static> ComponentCollection for EventChannel<E> {
+    /// Events don't belong to entities
+    fn remove_entity(&mut self, _entity_id : u64) {}
+
+    fn as_any(&self) -> &Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut Any {
+        self
+    }
+
+    fn changes(&self) -> &Changes {
+        &self.changes
+    }
+
+    fn changes_mut(&mut self) -> &mut Changes {
+        &mut self.changes
+    }
+
+    fn maintain(&mut self) {
+        EventChannel::maintain(self);
+    }
+}
+
+/*************************************************/
+/* The cursor of a single reader of a channel    */
+/*************************************************/
+pub struct ReaderId<E> {
+    cursor : Arc<AtomicU64>,
+    marker : PhantomData<fn(E)>,
+}
+
+/*************************************************/
+/* Iterates over the unread events of a reader   */
+/*************************************************/
+pub struct EventIter<'a, E : 'a> {
+    events : vec_deque::Iter<'a, (u64, u64, E)>,
+    cursor : &'a AtomicU64,
+}
+
+impl<'a, E> Iterator for EventIter<'a, E> {
+    type Item = &'a E;
+
+    fn next(&mut self) -> Option<&'a E> {
+        let (sequence, _, event) = self.events.next()?;
+        self.cursor.store(sequence + 1, Ordering::SeqCst);
+        Some(event)
+    }
+}
+
+/*************************************************/
+/* Unit Tests                                    */
+/*************************************************/
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use resources::{Resources, ResourceRequest, ResourceToken};
+    use systems::System;
+    use dispatcher::Dispatcher;
+    use state::Trans;
+
+    #[test]
+    fn test_readers() {
+        let mut channel = EventChannel::new();
+        let mut first = channel.register_reader();
+        channel.iter_write(vec!(1, 2));
+        let mut second = channel.register_reader();
+        channel.single_write(3);
+
+        assert!(channel.read(&mut first).cloned().collect::<Vec<u32>>() == vec!(1, 2, 3));
+        assert!(channel.read(&mut second).cloned().collect::<Vec<u32>>() == vec!(3));
+        assert!(channel.read(&mut first).next().is_none());
+
+        // events the reader stopped short of are read again
+        channel.iter_write(vec!(5, 6));
+        assert!(channel.read(&mut first).next() == Some(&5));
+        assert!(channel.read(&mut first).cloned().collect::<Vec<u32>>() == vec!(6));
+        assert!(channel.read(&mut second).cloned().collect::<Vec<u32>>() == vec!(5, 6));
+
+        // every reader has seen every event
+        channel.single_write(4);
+        channel.maintain();
+        assert!(channel.len() == 1);
+        assert!(channel.read(&mut second).cloned().collect::<Vec<u32>>() == vec!(4));
+        channel.maintain();
+        assert!(channel.len() == 1);
+
+        // dropped readers no longer hold events back
+        drop(first);
+        channel.maintain();
+        assert!(channel.is_empty());
+    }
+
+    #[test]
+    fn test_lifetime() {
+        let mut channel = EventChannel::with_lifetime(1);
+        let mut reader = channel.register_reader();
+        channel.single_write(1);
+        channel.maintain();
+        channel.single_write(2);
+        assert!(channel.len() == 2);
+
+        // the first event is dropped even though it was never read
+        channel.maintain();
+        assert!(channel.read(&mut reader).cloned().collect::<Vec<u32>>() == vec!(2));
+    }
+
+    struct Collision(u64);
+
+    struct Physics {
+        request : ResourceRequest,
+        frame : u64,
+    }
+    impl System for Physics {
+        fn resources(&self) -> Option<&ResourceRequest> {
+            Some(&self.request)
+        }
+
+        fn update(&mut self, token : ResourceToken) -> Trans {
+            let token = token.request(&self.request).unwrap();
+            self.frame += 1;
+            token.events_mut::<Collision>().unwrap().single_write(Collision(self.frame));
+            Trans::None
+        }
+    }
+
+    struct Audio {
+        request : ResourceRequest,
+        reader : Option<ReaderId<Collision>>,
+        heard : Arc<Mutex<Vec<u64>>>,
+    }
+    impl System for Audio {
+        fn start(&mut self, token : ResourceToken) {
+            let token = token.request(&self.request).unwrap();
+            self.reader = Some(token.events::<Collision>().unwrap().register_reader());
+        }
+
+        fn resources(&self) -> Option<&ResourceRequest> {
+            Some(&self.request)
+        }
+
+        fn update(&mut self, token : ResourceToken) -> Trans {
+            let token = token.request(&self.request).unwrap();
+            let reader = self.reader.as_mut().unwrap();
+            for collision in token.events::<Collision>().unwrap().read(reader) {
+                self.heard.lock().unwrap().push(collision.0);
+            }
+            Trans::None
+        }
+    }
+
+    #[test]
+    fn test_systems() {
+        let resources = Arc::new(Resources::new());
+        resources.register_events::<Collision>();
+        let heard = Arc::new(Mutex::new(Vec::new()));
+
+        let mut writer = ResourceRequest::new();
+        writer.write_events::<Collision>();
+        let mut reader = ResourceRequest::new();
+        reader.read_events::<Collision>();
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.with(Box::new(Physics { request : writer, frame : 0 }));
+        dispatcher.with(Box::new(Audio { request : reader, reader : None, heard : heard.clone() }));
+
+        dispatcher.on_start(resources.clone());
+        for _ in 0..3 {
+            dispatcher.on_update(resources.clone());
+            resources.maintain(dispatcher.oldest_run().unwrap());
+        }
+        assert!(*heard.lock().unwrap() == vec!(1, 2, 3));
+
+        // the events were dropped once they were heard
+        let mut request = ResourceRequest::new();
+        request.read_events::<Collision>();
+        let token = resources.get_token().request(&request).unwrap();
+        assert!(token.events::<Collision>().unwrap().is_empty());
+    }
+}
@@ -4,17 +4,27 @@ mod syncmap;
 mod state;
 mod systems;
 mod dispatcher;
+#[macro_use]
+mod storage;
 mod resources;
 mod entity;
+mod query;
+mod events;
+mod commands;
+mod time;
 
 use systems::System;
-use resources::{Component, ResourceRequest, ResourceToken};
+use resources::{Component, ComponentVector, ResourceRequest, ResourceToken};
 use state::{State, StateMachine, Trans};
 
 struct CompInt(u32);
 struct CompFloat(f32);
-impl Component for CompInt{}
-impl Component for CompFloat{}
+impl Component for CompInt{
+    type Storage = ComponentVector<Self>;
+}
+impl Component for CompFloat{
+    type Storage = ComponentVector<Self>;
+}
 
 struct SystemA {
     resources : ResourceRequest
@@ -22,25 +32,24 @@ struct SystemA {
 
 impl System for SystemA {
     fn start(&mut self, token : ResourceToken) {
-        // register components this system will use
-        token.register::<CompInt>();
-        token.register::<CompFloat>();
-
-        // fill out the request on how components will be used
+        // fill out the request on how components will be used,
+        // the components are registered the first time they are requested
         self.resources.write::<CompInt>()
                       .write::<CompFloat>();
 
         // Lets get some resources and initialize defualt values
-        let loan_token = token.request(&self.resources);
+        let loan_token = token.request(&self.resources).unwrap();
 
-        // once the resources are retrieved, we will need to unpack them
-        let ints = loan_token.unpack_mut::<CompInt>().unwrap();
-        let float = loan_token.unpack_mut::<CompFloat>().unwrap();
+        // build a new entity, its components are inserted into the loaned collections
+        loan_token.create_entity()
+                .with(CompInt(0))
+                .with(CompFloat(5.5))
+                .build()
+                .unwrap();
+    }
 
-        // register a new entity with the components
-        loan_token.register_entity()
-                .with(CompInt(0), ints)
-                .with(CompFloat(5.5), float);
+    fn resources(&self) -> Option<&ResourceRequest> {
+        Some(&self.resources)
     }
 
     fn update(&mut self, token : ResourceToken) -> Trans {
@@ -0,0 +1,471 @@
+use std::marker::PhantomData;
+use std::cell::RefMut;
+use std::slice;
+use std::collections::HashMap;
+use rayon::prelude::*;
+use rayon::iter::plumbing::UnindexedConsumer;
+use resources::{Component, ComponentCollection, ResourceToken};
+use storage::{Storage, ComponentPtr};
+use entity::Entity;
+
+/*************************************************/
+/* Markers describing how a Component is joined  */
+/*************************************************/
+/// Joins over a Component that was loaned with read permisions
+pub struct Read<C : Component>(PhantomData<C>);
+
+/// Joins over a Component that was loaned with write permisions,
+/// every matched component is marked as changed
+pub struct Write<C : Component>(PhantomData<C>);
+
+/// Joins over a Component loaned with read permisions, only matching
+/// components that were added since the system last ran
+pub struct Added<C : Component>(PhantomData<C>);
+
+/// Joins over a Component loaned with read permisions, only matching
+/// components that were added or mutably accessed since the system last ran
+pub struct Changed<C : Component>(PhantomData<C>);
+
+/*************************************************/
+/* Borrows a single collection out of a Loan     */
+/*************************************************/
+pub trait Fetch<'t> {
+    /// The collection borrowed from the loan
+    type Source;
+    /// The value produced for every matched entity, borrowed from the
+    /// Join for as long as the Join is iterated over
+    type Item<'j> where 't : 'j;
+
+    /// Borrows the collection out of the tokens loan, returns None
+    /// if the loan does not contain the collection
+    fn fetch(token : &'t ResourceToken) -> Option<Self::Source>;
+
+    /// Returns the entities that can drive a join, optional
+    /// fetches return None since they never limit a join
+    fn entities(source : &Self::Source) -> Option<Vec<u64>>;
+
+    /// Returns the item stored under the entity id, disabled components
+    /// are treated as missing. The caller must not ask for the same
+    /// entity twice while a previous item of the entity is alive
+    unsafe fn get<'j>(source : &'j Self::Source, entity_id : u64) -> Option<Self::Item<'j>> where 't : 'j;
+}
+
+/// A collection borrowed mutably by a Join. The guard keeps the collection unpacked
+/// while the Join is alive, the components are resolved to pointers once when the
+/// Join is made so handing out an item never borrows the whole storage again
+pub struct WriteSource<'t, C : Component> {
+    _guard : Guard<'t, C::Storage>,
+    entities : Vec<u64>,
+    pointers : HashMap<u64, ComponentPtr<C>>,
+    tick : u64,
+}
+
+/// Holds a collection unpacked, the guard can't be reached through a shared
+/// reference so sharing it between threads gives no access to the collection
+struct Guard<'t, S : 't>(RefMut<'t, S>);
+
+unsafe impl<'t, S> Sync for Guard<'t, S> {}
+
+impl<'t, C : Component> Fetch<'t> for Read<C> {
+    type Source = &'t C::Storage;
+    type Item<'j> = &'j C where 't : 'j;
+
+    fn fetch(token : &'t ResourceToken) -> Option<Self::Source> {
+        token.unpack::<C>().ok()
+    }
+
+    fn entities(source : &Self::Source) -> Option<Vec<u64>> {
+        Some(source.entity_ids())
+    }
+
+    unsafe fn get<'j>(source : &'j Self::Source, entity_id : u64) -> Option<Self::Item<'j>> where 't : 'j {
+        if !source.is_enabled(entity_id) {
+            return None;
+        }
+        source.get(entity_id)
+    }
+}
+
+impl<'t, C : Component> Fetch<'t> for Write<C> {
+    type Source = WriteSource<'t, C>;
+    type Item<'j> = &'j mut C where 't : 'j;
+
+    fn fetch(token : &'t ResourceToken) -> Option<Self::Source> {
+        let mut guard = token.unpack_mut::<C>().ok()?;
+        let entities = guard.entity_ids();
+        let tick = guard.changes().tick();
+        let pointers = guard.pointers().into_iter().collect();
+        Some(WriteSource {
+            _guard : Guard(guard),
+            entities : entities,
+            pointers : pointers,
+            tick : tick,
+        })
+    }
+
+    fn entities(source : &Self::Source) -> Option<Vec<u64>> {
+        Some(source.entities.clone())
+    }
+
+    unsafe fn get<'j>(source : &'j Self::Source, entity_id : u64) -> Option<Self::Item<'j>> where 't : 'j {
+        // The guard outlives 'j and every pointer is to a different component,
+        // so the references handed out never alias
+        source.pointers.get(&entity_id).map(|pointer| pointer.get_mut(source.tick))
+    }
+}
+
+impl<'t, C : Component> Fetch<'t> for Added<C> {
+    type Source = (&'t C::Storage, u64);
+    type Item<'j> = &'j C where 't : 'j;
+
+    fn fetch(token : &'t ResourceToken) -> Option<Self::Source> {
+        token.unpack::<C>().ok().map(|storage| (storage, token.last_run()))
+    }
+
+    fn entities(source : &Self::Source) -> Option<Vec<u64>> {
+        Some(source.0.entity_ids())
+    }
+
+    unsafe fn get<'j>(source : &'j Self::Source, entity_id : u64) -> Option<Self::Item<'j>> where 't : 'j {
+        let (storage, last_run) = *source;
+        if !storage.is_enabled(entity_id) || !storage.is_added(entity_id, last_run) {
+            return None;
+        }
+        storage.get(entity_id)
+    }
+}
+
+impl<'t, C : Component> Fetch<'t> for Changed<C> {
+    type Source = (&'t C::Storage, u64);
+    type Item<'j> = &'j C where 't : 'j;
+
+    fn fetch(token : &'t ResourceToken) -> Option<Self::Source> {
+        token.unpack::<C>().ok().map(|storage| (storage, token.last_run()))
+    }
+
+    fn entities(source : &Self::Source) -> Option<Vec<u64>> {
+        Some(source.0.entity_ids())
+    }
+
+    unsafe fn get<'j>(source : &'j Self::Source, entity_id : u64) -> Option<Self::Item<'j>> where 't : 'j {
+        let (storage, last_run) = *source;
+        if !storage.is_enabled(entity_id) || !storage.is_changed(entity_id, last_run) {
+            return None;
+        }
+        storage.get(entity_id)
+    }
+}
+
+impl<'t, F : Fetch<'t>> Fetch<'t> for Option<F> {
+    type Source = Option<F::Source>;
+    type Item<'j> = Option<F::Item<'j>> where 't : 'j;
+
+    fn fetch(token : &'t ResourceToken) -> Option<Self::Source> {
+        Some(F::fetch(token))
+    }
+
+    fn entities(_source : &Self::Source) -> Option<Vec<u64>> {
+        None
+    }
+
+    unsafe fn get<'j>(source : &'j Self::Source, entity_id : u64) -> Option<Self::Item<'j>> where 't : 'j {
+        match *source {
+            Some(ref source) => Some(F::get(source, entity_id)),
+            None => Some(None),
+        }
+    }
+}
+
+/*************************************************/
+/* A tuple of Fetches joined on the entity id    */
+/*************************************************/
+pub trait Query<'t> {
+    /// The collections borrowed from the loan
+    type Sources;
+    /// The tuple produced for every matched entity
+    type Items<'j> where 't : 'j;
+
+    /// Borrows every collection in the query out of the tokens loan
+    fn fetch(token : &'t ResourceToken) -> Option<Self::Sources>;
+
+    /// Returns the smallest set of entities that can drive the join
+    fn entities(sources : &Self::Sources) -> Option<Vec<u64>>;
+
+    /// Returns the items for the entity only if every required
+    /// collection contains the entity
+    unsafe fn get<'j>(sources : &'j Self::Sources, entity_id : u64) -> Option<Self::Items<'j>> where 't : 'j;
+}
+
+macro_rules! impl_query {
+    ($($fetch:ident),+) => {
+        #[allow(non_snake_case)]
+        impl<'t, $($fetch : Fetch<'t>),+> Query<'t> for ($($fetch,)+) {
+            type Sources = ($($fetch::Source,)+);
+            type Items<'j> = ($($fetch::Item<'j>,)+) where 't : 'j;
+
+            fn fetch(token : &'t ResourceToken) -> Option<Self::Sources> {
+                Some(($($fetch::fetch(token)?,)+))
+            }
+
+            fn entities(sources : &Self::Sources) -> Option<Vec<u64>> {
+                let ($(ref $fetch,)+) = *sources;
+                let mut smallest : Option<Vec<u64>> = None;
+                $(
+                    if let Some(entities) = $fetch::entities($fetch) {
+                        let smaller = match smallest {
+                            Some(ref current) => entities.len() < current.len(),
+                            None => true,
+                        };
+                        if smaller {
+                            smallest = Some(entities);
+                        }
+                    }
+                )+
+                smallest
+            }
+
+            unsafe fn get<'j>(sources : &'j Self::Sources, entity_id : u64) -> Option<Self::Items<'j>> where 't : 'j {
+                let ($(ref $fetch,)+) = *sources;
+                Some(($($fetch::get($fetch, entity_id)?,)+))
+            }
+        }
+    };
+}
+
+impl_query!(A);
+impl_query!(A, B);
+impl_query!(A, B, C);
+impl_query!(A, B, C, D);
+impl_query!(A, B, C, D, E);
+impl_query!(A, B, C, D, E, F);
+impl_query!(A, B, C, D, E, F, G);
+impl_query!(A, B, C, D, E, F, G, H);
+
+/*************************************************/
+/* The collections borrowed by a Query           */
+/*************************************************/
+/// A Join holds the collections of the query for as long as it is alive.
+/// The items are borrowed from the Join, so they can't outlive it and only
+/// one iterator over the Join can be alive at a time
+pub struct Join<'t, Q : Query<'t>> {
+    sources : Q::Sources,
+    entities : Vec<u64>,
+}
+
+impl<'t, Q : Query<'t>> Join<'t, Q> {
+    /// Borrows the collections of the query from the token, returns None
+    /// if a required collection is not part of the tokens loan
+    pub(crate) fn new(token : &'t ResourceToken) -> Option<Join<'t, Q>> {
+        let sources = Q::fetch(token)?;
+        let entities = Q::entities(&sources).unwrap_or_default();
+        Some(Join {
+            sources : sources,
+            entities : entities,
+        })
+    }
+
+    /// Iterates over the matched entities in the order of the collection driving the join
+    pub fn iter_mut(&mut self) -> JoinIter<'_, 't, Q> {
+        JoinIter {
+            sources : &self.sources,
+            entities : self.entities.iter(),
+        }
+    }
+
+    /// Matches the entities on rayon's thread pool, the entities
+    /// are visited in no particular order
+    pub fn par_iter(&mut self) -> JoinParIter<'_, 't, Q> {
+        JoinParIter {
+            sources : &self.sources,
+            entities : &self.entities,
+        }
+    }
+}
+
+impl<'j, 't, Q : Query<'t>> IntoIterator for &'j mut Join<'t, Q> {
+    type Item = (Entity, Q::Items<'j>);
+    type IntoIter = JoinIter<'j, 't, Q>;
+
+    fn into_iter(self) -> JoinIter<'j, 't, Q> {
+        self.iter_mut()
+    }
+}
+
+/*************************************************/
+/* Iterates over the entities matched by a Query */
+/*************************************************/
+pub struct JoinIter<'j, 't : 'j, Q : Query<'t> + 'j> {
+    sources : &'j Q::Sources,
+    entities : slice::Iter<'j, u64>,
+}
+
+impl<'j, 't, Q : Query<'t>> Iterator for JoinIter<'j, 't, Q> {
+    type Item = (Entity, Q::Items<'j>);
+
+    fn next(&mut self) -> Option<(Entity, Q::Items<'j>)> {
+        let sources = self.sources;
+        // every entity is only visited once while the join is mutably borrowed
+        self.entities.by_ref()
+            .filter_map(|id| unsafe { Q::get(sources, *id) }.map(|items| (Entity::new_with_id(*id), items)))
+            .next()
+    }
+}
+
+/*************************************************/
+/* Parallel Iterator over the matches of a Join  */
+/*************************************************/
+pub struct JoinParIter<'j, 't : 'j, Q : Query<'t> + 'j> {
+    sources : &'j Q::Sources,
+    entities : &'j [u64],
+}
+
+impl<'j, 't, Q : Query<'t>> ParallelIterator for JoinParIter<'j, 't, Q>
+    where Q::Sources : Sync, Q::Items<'j> : Send {
+    type Item = (Entity, Q::Items<'j>);
+
+    /// The entity list is split across the thread pool and
+    /// every thread matches its own entities
+    fn drive_unindexed<C>(self, consumer : C) -> C::Result where C : UnindexedConsumer<Self::Item> {
+        let sources = self.sources;
+        self.entities.par_iter()
+            .filter_map(move |id| unsafe { Q::get(sources, *id) }.map(|items| (Entity::new_with_id(*id), items)))
+            .drive_unindexed(consumer)
+    }
+}
+
+/*************************************************/
+/* Unit Tests                                    */
+/*************************************************/
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use resources::{Resources, ResourceRequest};
+
+    use resources::ComponentVector;
+    use storage::{HashMapStorage, NullStorage};
+
+    struct Pos(u64);
+    struct Vel(u64);
+    struct Tag;
+    impl Component for Pos {
+        type Storage = ComponentVector<Self>;
+    }
+    impl Component for Vel {
+        type Storage = ComponentVector<Self>;
+    }
+    impl Component for Tag {
+        type Storage = NullStorage<Self>;
+    }
+    struct Heat(u64);
+    impl Component for Heat {
+        type Storage = HashMapStorage<Self>;
+    }
+
+    #[test]
+    fn test_join() {
+        let resources = Resources::new();
+        resources.register::<Pos>();
+        resources.register::<Vel>();
+        resources.register::<Tag>();
+
+        let mut request = ResourceRequest::new();
+        request.write::<Pos>().write::<Vel>().write::<Tag>();
+        let token = resources.get_token().request(&request).unwrap();
+
+        // entity 0 has Pos and Vel, entity 1 only Pos, entity 2 has all three
+        token.register_entity()
+            .with(Pos(0), token.unpack_mut::<Pos>().unwrap())
+            .with(Vel(10), token.unpack_mut::<Vel>().unwrap());
+        token.register_entity()
+            .with(Pos(1), token.unpack_mut::<Pos>().unwrap());
+        token.register_entity()
+            .with(Pos(2), token.unpack_mut::<Pos>().unwrap())
+            .with(Vel(20), token.unpack_mut::<Vel>().unwrap())
+            .with(Tag, token.unpack_mut::<Tag>().unwrap());
+
+        // Move every entity that has a velocity
+        for (_, (pos, vel)) in token.join::<(Write<Pos>, Write<Vel>)>().unwrap().iter_mut() {
+            pos.0 += vel.0;
+        }
+
+        let moved : Vec<(u64, u64)> = token.join::<(Write<Pos>,)>().unwrap().iter_mut()
+            .map(|(entity, (pos,))| (entity.id(), pos.0))
+            .collect();
+        assert!(moved == vec!((0, 10), (1, 1), (2, 22)));
+
+        let tagged : Vec<(u64, bool)> = token.join::<(Write<Pos>, Option<Write<Tag>>)>().unwrap().iter_mut()
+            .map(|(entity, (_, tag))| (entity.id(), tag.is_some()))
+            .collect();
+        assert!(tagged == vec!((0, false), (1, false), (2, true)));
+
+        // the join keeps Pos unpacked while its items are alive
+        let mut join = token.join::<(Write<Pos>,)>().unwrap();
+        assert!(token.unpack_mut::<Pos>().is_err());
+        assert!(token.join::<(Write<Pos>,)>().is_none());
+        for (_, (pos,)) in &mut join {
+            pos.0 += 1;
+        }
+        drop(join);
+        assert!(token.get_mut::<Pos>(Entity::new_with_id(1)).unwrap().0 == 2);
+
+        // the items of a join can all be held at the same time
+        let mut join = token.join::<(Write<Pos>,)>().unwrap();
+        let items : Vec<_> = join.iter_mut().collect();
+        for (_, (pos,)) in items {
+            pos.0 *= 2;
+        }
+        drop(join);
+        assert!(token.get_mut::<Pos>(Entity::new_with_id(2)).unwrap().0 == 46);
+    }
+
+    #[test]
+    fn test_par_join() {
+        let resources = Resources::new();
+        resources.register::<Pos>();
+        resources.register::<Vel>();
+
+        let mut request = ResourceRequest::new();
+        request.write::<Pos>().write::<Vel>().write::<Heat>();
+        let token = resources.get_token().request(&request).unwrap();
+        for id in 0..100 {
+            let entity = token.register_entity()
+                .with(Pos(id), token.unpack_mut::<Pos>().unwrap())
+                .with(Heat(0), token.unpack_mut::<Heat>().unwrap());
+            if id % 2 == 0 {
+                entity.with(Vel(1), token.unpack_mut::<Vel>().unwrap());
+            }
+        }
+
+        token.join::<(Write<Pos>, Write<Vel>, Write<Heat>)>().unwrap().par_iter()
+            .for_each(|(_, (pos, vel, heat))| {
+                pos.0 += vel.0;
+                heat.0 += 1;
+            });
+        let moved = token.join::<(Write<Pos>,)>().unwrap().iter_mut()
+            .filter(|(entity, (pos,))| pos.0 != entity.id())
+            .count();
+        assert!(moved == 50);
+        let heated = token.join::<(Write<Heat>,)>().unwrap().iter_mut()
+            .filter(|(_, (heat,))| heat.0 == 1)
+            .count();
+        assert!(heated == 50);
+    }
+
+    #[test]
+    fn test_join_requires_loan() {
+        let resources = Resources::new();
+        resources.register::<Pos>();
+        resources.register::<Vel>();
+
+        let mut request = ResourceRequest::new();
+        request.read::<Pos>();
+        let token = resources.get_token().request(&request).unwrap();
+
+        // Vel was never loaned so the join can not be built
+        assert!(token.join::<(Read<Pos>, Read<Vel>)>().is_none());
+        assert!(token.join::<(Read<Pos>, Option<Read<Vel>>)>().is_some());
+
+        // Write access can not be granted through a read loan
+        assert!(token.join::<(Write<Pos>,)>().is_none());
+    }
+}
@@ -1,45 +1,109 @@
 use std::ops::Range;
-use std::any::{TypeId, Any};
-use entity::{Entity,EntityRegister};
+use std::any::{TypeId, Any, type_name};
+use std::collections::HashMap;
+use entity::{Entity,EntityRegister,EntityBuilder,Bundle,GENERATION_BITS,index_of};
 use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
 use bit_field::BitField;
-use std::cell::RefMut;
-use std::mem::transmute;
-use syncmap::{SyncMap,Request,Loan};
+use std::cell::{RefCell, RefMut};
+use std::ops::Deref;
+use std::marker::PhantomData;
+use std::mem::replace;
+use syncmap::{SyncMap,SyncMapError,Request,Loan,Diagnostics};
+use std::time::Duration;
+use std::{fmt,error};
+use std::error::Error;
+use std::fmt::Display;
+use query::{Query,Join};
+use events::EventChannel;
+use commands::{Commands, Command};
+use storage::{Storage, Changes, ComponentPtr};
+use rayon::prelude::*;
+use rayon::slice;
+use rayon::iter::plumbing::UnindexedConsumer;
 
 const ENTITY_BITS : Range<usize> = 0..36;
 const NEXT_BITS : Range<usize> = 36..63;
 const IS_ON_BIT : Range<usize> = 63..64;
+const PREV_BITS : Range<usize> = 0..27;
 
 /*************************************************/
 /* Trait Stored in the ComponentCollections      */
 /*************************************************/
-pub trait Component: Any + Send + Sync {}
+pub trait Component: Any + Send + Sync + Sized {
+    /// The collection the Component is stored in, ComponentVector
+    /// is a good default for most Components
+    type Storage : Storage<Self>;
+}
 
 /*************************************************/
 /* Trait of a Homogenous Collection of Components*/
 /*************************************************/
-pub trait ComponentCollection : Send + Sync {}
+pub trait ComponentCollection : Send + Sync {
+    /// Removes any component belonging to the entity
+    fn remove_entity(&mut self, entity_id : u64);
+
+    /// Returns the collection as Any so it can be downcast to its Storage type
+    fn as_any(&self) -> &Any;
+
+    /// Returns the collection as Any so it can be mutably downcast to its Storage type
+    fn as_any_mut(&mut self) -> &mut Any;
+
+    /// Returns the changes recorded by the collection
+    fn changes(&self) -> &Changes;
+
+    /// Returns the changes recorded by the collection mutably
+    fn changes_mut(&mut self) -> &mut Changes;
+
+    /// Called once at the end of every frame
+    fn maintain(&mut self) {}
+}
 
 /*************************************************/
 /* Wrapper which stores Components and Meta Data */
 /*************************************************/
-struct ComponentWrapper<D : Component> {
-    component : D,
+pub struct ComponentWrapper<D : Component> {
+    pub(crate) component : D,
     meta : u64,
+    info : u64,
+    added : u64,
+    changed : u64,
 }
 
 impl<D : Component> ComponentWrapper<D> {
     /// Constructs a new Component Wrapper, and initializes meta data
-    fn new(component: D, entity_id : u64, next : u64, active : bool) -> ComponentWrapper<D> {
+    pub(crate) fn new(component: D, entity_id : u64, next : u64, active : bool, tick : u64) -> ComponentWrapper<D> {
         ComponentWrapper {
             component: component,
-            meta: *0.set_bits(ENTITY_BITS, entity_id)
+            meta: *0.set_bits(ENTITY_BITS, index_of(entity_id))
                     .set_bits(NEXT_BITS, next)
                     .set_bits(IS_ON_BIT, active as u64),
+            info: *0.set_bits(GENERATION_BITS, entity_id.get_bits(GENERATION_BITS)),
+            added: tick,
+            changed: tick,
         }
     }
 
+    /// Returns the tick the component was added at
+    pub(crate) fn added(&self) -> u64 {
+        self.added
+    }
+
+    /// Returns the tick the component was last added or mutably accessed at
+    pub(crate) fn changed(&self) -> u64 {
+        self.changed
+    }
+
+    /// Marks the component as changed at the tick
+    pub(crate) fn mark_changed(&mut self, tick : u64) {
+        self.changed = tick;
+    }
+
+    /// Returns a pointer to the component and to the tick it was changed at
+    pub(crate) fn pointer(&mut self) -> ComponentPtr<D> {
+        ComponentPtr::new(&mut self.component, &mut self.changed)
+    }
+
     /// Sets the next field of the meta data
     fn set_next(&mut self, next : u64){
         self.meta.set_bits(NEXT_BITS, next);
@@ -50,8 +114,32 @@ impl<D : Component> ComponentWrapper<D> {
         self.meta.get_bits(NEXT_BITS)
     }
 
-    fn get_entity(&self) -> u64 {
-        self.meta.get_bits(ENTITY_BITS)
+    /// Sets the index of the previous component in the list
+    fn set_prev(&mut self, prev : u64){
+        self.info.set_bits(PREV_BITS, prev);
+    }
+
+    /// Returns the index of the previous component in the list
+    fn get_prev(&self) -> u64 {
+        self.info.get_bits(PREV_BITS)
+    }
+
+    /// Turns the component on or off, components that are off
+    /// are skipped when iterating or joining
+    pub(crate) fn set_on(&mut self, on : bool) {
+        self.meta.set_bits(IS_ON_BIT, on as u64);
+    }
+
+    /// Returns true if the component is on
+    pub(crate) fn is_on(&self) -> bool {
+        self.meta.get_bit(IS_ON_BIT.start)
+    }
+
+    /// Returns the id of the entity, the index is stored in the meta
+    /// data and the generation alongside the previous pointer
+    pub(crate) fn get_entity(&self) -> u64 {
+        *self.meta.get_bits(ENTITY_BITS)
+                  .set_bits(GENERATION_BITS, self.info.get_bits(GENERATION_BITS))
     }
 }
 
@@ -60,131 +148,227 @@ impl<D : Component> ComponentWrapper<D> {
 /*************************************************/
 pub struct ComponentVector<D : Component> {
     components : Vec<ComponentWrapper<D>>,
+    index : HashMap<u64, usize>,
     type_id: TypeId,
     head: usize,
     tail: usize,
+    changes: Changes,
 }
 
 impl<D : Component> ComponentVector<D> {
     fn new() -> ComponentVector<D> {
         ComponentVector {
             components : Vec::new(),
+            index : HashMap::new(),
             type_id: TypeId::of::<D>(),
             head: 0,
             tail: 0,
+            changes: Changes::new(),
         }
     }
 
-    fn iter(&self) -> ComponentVectorIter<D> {
+    /// Iterates over the wrappers in insertion order
+    fn list(&self) -> ComponentVectorIter<D> {
         ComponentVectorIter::new(self)
     }
 
+    /// Returns the wrapper stored under the entity
+    fn wrapper(&self, entity_id : u64) -> Option<&ComponentWrapper<D>> {
+        match self.index.get(&entity_id) {
+            Some(index) => Some(&self.components[*index]),
+            None => None,
+        }
+    }
+
+    /// Returns the wrapper stored under the entity mutably
+    fn wrapper_mut(&mut self, entity_id : u64) -> Option<&mut ComponentWrapper<D>> {
+        match self.index.get(&entity_id) {
+            Some(index) => Some(&mut self.components[*index]),
+            None => None,
+        }
+    }
+
+    /// Iterates over the wrappers in insertion order
+    fn wrappers<'a>(&'a self) -> Box<Iterator<Item = &'a ComponentWrapper<D>> + 'a> {
+        Box::new(self.list())
+    }
+
+    /// Iterates mutably over the wrappers in the order they are stored
+    fn wrappers_mut<'a>(&'a mut self) -> Box<Iterator<Item = &'a mut ComponentWrapper<D>> + 'a> {
+        Box::new(self.components.iter_mut())
+    }
+
+    /// Iterates over the enabled components in insertion order along with their entity
+    pub fn iter(&self) -> CompVecIter<D> {
+        CompVecIter::new(self, false)
+    }
+
+    /// Iterates over every component in insertion order, including disabled ones
+    pub fn iter_all(&self) -> CompVecIter<D> {
+        CompVecIter::new(self, true)
+    }
+
+    /// Iterates mutably over the enabled components in insertion order along
+    /// with their entity, every component visited is marked as changed
+    pub fn iter_mut(&mut self) -> CompVecIterMut<D> {
+        CompVecIterMut::new(self)
+    }
+
+    /// Iterates over the enabled components on rayon's thread pool,
+    /// the components are visited in no particular order
+    pub fn par_iter(&self) -> CompVecParIter<D> {
+        CompVecParIter {
+            iter : self.components.par_iter(),
+        }
+    }
+
+    /// Iterates mutably over the enabled components on rayon's thread pool, the
+    /// components are visited in no particular order and marked as changed
+    pub fn par_iter_mut(&mut self) -> CompVecParIterMut<D> {
+        CompVecParIterMut {
+            iter : self.components.par_iter_mut(),
+            tick : self.changes.tick(),
+        }
+    }
+
+    /// Iterates over the entities that have an enabled component in the collection
+    pub fn entities<'a>(&'a self) -> Box<Iterator<Item = Entity> + 'a> {
+        Box::new(self.iter().map(|(entity, _)| entity))
+    }
+
     fn len(&self) -> usize {
         self.components.len()
     }
 
-    pub(crate) fn push(&mut self, component : D, entity_id : u64){
+    /// Adds the component to the entity, if the entity already has a
+    /// component in the collection it is replaced in place
+    pub(crate) fn push(&mut self, component : D, entity_id : u64) -> Option<D> {
+        // Replace the component if the entity already has one
+        let tick = self.changes.tick();
+        if let Some(index) = self.index.get(&entity_id) {
+            self.components[*index].mark_changed(tick);
+            return Some(replace(&mut self.components[*index].component, component));
+        }
+
+        // Get the new index for the new component
+        let new_index = self.components.len();
+
         // Check if this is the first Component to be added
         // to the collection
+        let mut wrapper = ComponentWrapper::new(component,entity_id,0,true,tick);
         if self.len() == 0 {
             self.head = 0;
             self.tail = 0;
         } else {
-            // Get the new index for the new component
-            let new_index = self.components.len();
-
             // Update the previous tail's next to point to the new index
-            if self.len() > self.tail {
-                self.components[self.tail].set_next(new_index as u64);
-            }
+            let tail = self.tail;
+            self.components[tail].set_next(new_index as u64);
+            wrapper.set_prev(tail as u64);
 
             // update the tail to point to the new index
             self.tail = new_index;
         }
 
         // Insert the new component into the vector
-        self.components.push(ComponentWrapper::new(component,entity_id,0,true))
+        self.index.insert(entity_id, new_index);
+        self.components.push(wrapper);
+        None
     }
 
-    fn remove(&mut self, entity_id : u64){
-        // Can't remove from empty vector
-        if self.len() == 0 {
-            return;
-        }
-
-        let mut prev: isize = -1;
-        let mut curr: isize = self.head as isize;
-        let mut found = false;
-        let mut end_prev = self.head as isize;
-        let mut found_end_prev = false;
-
-        // chances our end_prev = len() -2 so let's checkot
-        if self.len() >= 2 {
-            if self.components[self.len() - 2].get_next() == (self.len() - 1) as u64{
-                found_end_prev = true;
-                end_prev = (self.len() - 2) as isize;
-            } 
-        }
-
-        // Iterate over the structure till curr is found
-        // keep a reference to the previous
-        for value in self.iter(){
-            // Finds the entity being removed
-            if value.get_entity() == entity_id{
-                found = true;
-            } else if !found {
-                prev = curr;
-                curr = value.get_next() as isize;
-            }
+    /// Removes the component belonging to the entity. The last component in
+    /// the vector is swapped into the removed slot so removal is O(1)
+    fn remove(&mut self, entity_id : u64) -> Option<D> {
+        let curr = match self.index.remove(&entity_id) {
+            Some(curr) => curr,
+            None => return None,
+        };
+        let last = self.len() - 1;
+
+        // Unlink curr from the list
+        let prev = self.components[curr].get_prev() as usize;
+        let next = self.components[curr].get_next() as usize;
+        if curr == self.head {
+            self.head = next;
+        } else {
+            self.components[prev].set_next(next as u64);
+        }
+        if curr == self.tail {
+            self.tail = prev;
+        } else {
+            self.components[next].set_prev(prev as u64);
+        }
 
-            // finds the entity that points to the last node
-            if value.get_next() == (self.len() - 1) as u64{
-                found_end_prev = true;
-            } else if !found_end_prev {
-                end_prev = value.get_next() as isize;
+        // Since we will be swaping the end of the vector with the
+        // currently deleted, the neighbours of the last entry need
+        // to point at its new location
+        if curr != last {
+            let last_prev = self.components[last].get_prev() as usize;
+            let last_next = self.components[last].get_next() as usize;
+            if last == self.head {
+                self.head = curr;
+            } else {
+                self.components[last_prev].set_next(curr as u64);
             }
-
-            if found && found_end_prev {
-                break;
+            if last == self.tail {
+                self.tail = curr;
+            } else {
+                self.components[last_next].set_prev(curr as u64);
             }
+            let last_entity = self.components[last].get_entity();
+            self.index.insert(last_entity, curr);
         }
+        self.changes.record_removed(entity_id);
 
-        if found {
-            // Since we will be swaping the end of the vector with the
-            // currently deleted, we need to update the next pointer of the
-            // prev entry to reflect that.
-            if found_end_prev {
-                self.components[end_prev as usize].set_next(curr as u64);
-            }
-
-            let curr = curr as usize;
-            let next = self.components[curr as usize].get_next();
+        Some(self.components.swap_remove(curr).component)
+    }
+}
 
-            // update the prev pointer
-            if prev >= 0 {
-                self.components[prev as usize].set_next(next);
-            }
+impl<D : Component> ComponentCollection for ComponentVector<D> {
+    fn remove_entity(&mut self, entity_id : u64) {
+        self.remove(entity_id);
+    }
 
-            // If curr was the head of the list, set the new head
-            if curr == self.head {
-                self.head = next as usize;
-            }
+    fn as_any(&self) -> &Any {
+        self
+    }
 
-            // If curr was the tail of the list, set the tail pointer
-            if curr == self.tail {
-                self.tail = prev.max(0) as usize;
-            }
+    fn as_any_mut(&mut self) -> &mut Any {
+        self
+    }
 
-            if self.tail == self.len() - 1 {
-                self.tail = curr;
-            }
+    fn changes(&self) -> &Changes {
+        &self.changes
+    }
 
-            self.components.swap_remove(curr);
-        }
+    fn changes_mut(&mut self) -> &mut Changes {
+        &mut self.changes
     }
 }
 
-impl<D : Component> ComponentCollection for ComponentVector<D> {}
+impl<D : Component> Storage<D> for ComponentVector<D> {
+    fn new() -> ComponentVector<D> {
+        ComponentVector::new()
+    }
+
+    fn insert(&mut self, entity_id : u64, component : D) -> Option<D> {
+        self.push(component, entity_id)
+    }
+
+    fn remove(&mut self, entity_id : u64) -> Option<D> {
+        ComponentVector::remove(self, entity_id)
+    }
+
+    impl_wrapper_access!(D);
+
+    fn len(&self) -> usize {
+        self.components.len()
+    }
+
+    fn reserve(&mut self, additional : usize) {
+        self.components.reserve(additional);
+        self.index.reserve(additional);
+    }
+}
 
 /*************************************************/
 /* Iterator for Component Vector                 */
@@ -226,33 +410,146 @@ impl<'a, D: Component> Iterator for ComponentVectorIter<'a, D>{
 
 pub struct CompVecIter<'a, D : Component> {
     iter: ComponentVectorIter<'a, D>,
+    all: bool,
 }
 
 impl<'a, D : Component> CompVecIter<'a, D> {
-    fn new(vector : &'a ComponentVector<D>) -> CompVecIter<D> {
+    fn new(vector : &'a ComponentVector<D>, all : bool) -> CompVecIter<D> {
         CompVecIter {
             iter : ComponentVectorIter::new(vector), 
+            all : all,
         }
     }
 }
 
 impl<'a, D: Component> Iterator for CompVecIter<'a, D>{
-    type Item = &'a D;
+    type Item = (Entity, &'a D);
 
-    fn next(&mut self) -> Option<&'a D> {
-        match self.iter.next(){
-            Some(d) => Some(&d.component),
-            None => None,
+    fn next(&mut self) -> Option<(Entity, &'a D)> {
+        while let Some(d) = self.iter.next() {
+            if self.all || d.is_on() {
+                return Some((Entity::new_with_id(d.get_entity()), &d.component));
+            }
+        }
+        None
+    }
+}
+
+pub struct CompVecIterMut<'a, D : Component> {
+    current : usize,
+    remaining : usize,
+    components : *mut ComponentWrapper<D>,
+    len : usize,
+    tick : u64,
+    marker : PhantomData<&'a mut ComponentVector<D>>,
+}
+
+impl<'a, D : Component> CompVecIterMut<'a, D> {
+    fn new(vector : &'a mut ComponentVector<D>) -> CompVecIterMut<D> {
+        CompVecIterMut {
+            current : vector.head,
+            remaining : vector.len(),
+            components : vector.components.as_mut_ptr(),
+            len : vector.len(),
+            tick : vector.changes.tick(),
+            marker : PhantomData,
         }
     }
 }
 
+impl<'a, D: Component> Iterator for CompVecIterMut<'a, D>{
+    type Item = (Entity, &'a mut D);
+
+    fn next(&mut self) -> Option<(Entity, &'a mut D)> {
+        while self.remaining > 0 && self.current < self.len {
+            // Following the list visits every wrapper once
+            // so the references handed out never alias
+            let wrapper = unsafe { &mut *self.components.add(self.current) };
+            self.remaining -= 1;
+            self.current = wrapper.get_next() as usize;
+            if wrapper.is_on() {
+                wrapper.mark_changed(self.tick);
+                return Some((Entity::new_with_id(wrapper.get_entity()), &mut wrapper.component));
+            }
+        }
+        None
+    }
+}
+
+/*************************************************/
+/* Parallel Iterators for Component Vector       */
+/*************************************************/
+pub struct CompVecParIter<'a, D : Component> {
+    iter : slice::Iter<'a, ComponentWrapper<D>>,
+}
+
+impl<'a, D : Component> ParallelIterator for CompVecParIter<'a, D> {
+    type Item = (Entity, &'a D);
+
+    fn drive_unindexed<C>(self, consumer : C) -> C::Result where C : UnindexedConsumer<Self::Item> {
+        self.iter.filter(|wrapper| wrapper.is_on())
+            .map(|wrapper| (Entity::new_with_id(wrapper.get_entity()), &wrapper.component))
+            .drive_unindexed(consumer)
+    }
+}
+
+pub struct CompVecParIterMut<'a, D : Component> {
+    iter : slice::IterMut<'a, ComponentWrapper<D>>,
+    tick : u64,
+}
+
+impl<'a, D : Component> ParallelIterator for CompVecParIterMut<'a, D> {
+    type Item = (Entity, &'a mut D);
+
+    fn drive_unindexed<C>(self, consumer : C) -> C::Result where C : UnindexedConsumer<Self::Item> {
+        let tick = self.tick;
+        self.iter.filter(|wrapper| wrapper.is_on())
+            .map(move |wrapper| {
+                wrapper.mark_changed(tick);
+                (Entity::new_with_id(wrapper.get_entity()), &mut wrapper.component)
+            })
+            .drive_unindexed(consumer)
+    }
+}
+
+/*************************************************/
+/* Stores a single global resource               */
+/*************************************************/
+struct Unique<R : Send + Sync + 'static> {
+    value : R,
+    changes : Changes,
+}
+
+impl<R : Send + Sync + 'static> ComponentCollection for Unique<R> {
+    /// Resources don't belong to entities
+    fn remove_entity(&mut self, _entity_id : u64) {}
+
+    fn as_any(&self) -> &Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut Any {
+        self
+    }
+
+    fn changes(&self) -> &Changes {
+        &self.changes
+    }
+
+    fn changes_mut(&mut self) -> &mut Changes {
+        &mut self.changes
+    }
+}
+
 /*************************************************/
 /* Stores a Collection of ComponentCollections   */
 /*************************************************/
 pub struct Resources {
     component_collections : SyncMap<TypeId, Box<ComponentCollection>>,
     register: Mutex<EntityRegister>,
+    names: Mutex<HashMap<TypeId, &'static str>>,
+    tick: AtomicU64,
+    pending: Mutex<Vec<(usize, Vec<Command>)>>,
 }
 
 impl Resources {
@@ -262,21 +559,249 @@ impl Resources {
         Resources {
             component_collections: SyncMap::new(),
             register: Mutex::new(EntityRegister::new()),
+            names: Mutex::new(HashMap::new()),
+            tick: AtomicU64::new(0),
+            pending: Mutex::new(Vec::new()),
         }
     }
 
     pub fn register<T: Component>(&self){
-        let vec : ComponentVector<T> = ComponentVector::new();
-        let _ = self.component_collections.insert( TypeId::of::<T>(), Box::new(vec));
+        let storage : T::Storage = Storage::new();
+        self.names.lock().unwrap().insert(TypeId::of::<T>(), type_name::<T>());
+        let _ = self.component_collections.insert( TypeId::of::<T>(), Box::new(storage));
+    }
+
+    /// Returns true if the Component has been registered
+    pub fn contains<T : Component>(&self) -> bool {
+        self.component_collections.contains_key(&TypeId::of::<T>()).unwrap()
+    }
+
+    /// Allocates a new Entity
+    pub(crate) fn allocate(&self) -> Entity {
+        self.register.lock().unwrap().allocate()
+    }
+
+    /// Reserves a range of fresh Entity ids with a single lock of the register
+    pub(crate) fn allocate_range(&self, count : u64) -> Range<u64> {
+        self.register.lock().unwrap().register(count)
+    }
+
+    /// Queues the commands of a token to be applied at the next sync point
+    fn defer(&self, owner : usize, commands : Vec<Command>) {
+        self.pending.lock().unwrap().push((owner, commands));
+    }
+
+    /// Applies the queued commands in the order of the systems that queued them,
+    /// this waits for loans on the collections involved so it must not be
+    /// called while holding a loan
+    pub fn apply_commands(&self) {
+        let mut pending = replace(&mut *self.pending.lock().unwrap(), Vec::new());
+        pending.sort_by_key(|(owner, _)| *owner);
+        for (_, commands) in pending {
+            for command in commands {
+                command(self);
+            }
+        }
+    }
+
+    /// Inserts a singleton resource, such as a timer or a config, that is
+    /// loaned out like a component collection. Returns the resource it replaced,
+    /// replacing a resource waits for any loan of it to be returned
+    pub fn insert_resource<R : Send + Sync + 'static>(&self, resource : R) -> Option<R> {
+        let key = TypeId::of::<Unique<R>>();
+        self.names.lock().unwrap().insert(key, type_name::<R>());
+        if self.component_collections.contains_key(&key).unwrap() {
+            let mut request = Request::new();
+            request.write(key);
+            let loan = self.component_collections.request(&request).unwrap().unwrap();
+            let mut collection = loan.write(&key).unwrap();
+            let unique = collection.as_any_mut().downcast_mut::<Unique<R>>().unwrap();
+            return Some(replace(&mut unique.value, resource));
+        }
+        let _ = self.component_collections.insert(key, Box::new(Unique {
+            value : resource,
+            changes : Changes::new(),
+        }));
+        None
+    }
+
+    /// Registers an EventChannel for the event type that keeps
+    /// events until every reader has seen them
+    pub fn register_events<E : Send + Sync + 'static>(&self) {
+        self.register_channel::<E>(EventChannel::new());
+    }
+
+    /// Registers the EventChannel, useful for channels with a lifetime
+    pub fn register_channel<E : Send + Sync + 'static>(&self, channel : EventChannel<E>) {
+        self.names.lock().unwrap().insert(TypeId::of::<EventChannel<E>>(), type_name::<EventChannel<E>>());
+        let _ = self.component_collections.insert(TypeId::of::<EventChannel<E>>(), Box::new(channel));
+    }
+
+    /// Blocks until every resource in the request can be loaned out. With
+    /// deadlock detection enabled a Deadlock error is returned instead of
+    /// waiting on loans that can never be returned
+    pub fn request(&self, request : &ResourceRequest) -> Result<Loan<TypeId,Box<ComponentCollection>>,ResourceError> {
+        self.register_missing(request)?;
+        let loan = self.component_collections.request(&request.request).map_err(|e| self.error(e))?;
+        self.loaned(request, loan)
+    }
+
+    /// Loans out the resources in the request only if they are all available right away
+    pub fn try_request(&self, request : &ResourceRequest) -> Result<Loan<TypeId,Box<ComponentCollection>>,ResourceError> {
+        self.register_missing(request)?;
+        let loan = self.component_collections.try_request(&request.request).map_err(|e| self.error(e))?;
+        self.loaned(request, loan)
+    }
+
+    /// Waits up to the timeout for every resource in the request to be available
+    pub fn request_timeout(&self, request : &ResourceRequest, timeout : Duration) -> Result<Loan<TypeId,Box<ComponentCollection>>,ResourceError> {
+        self.register_missing(request)?;
+        let loan = self.component_collections.request_timeout(&request.request, timeout).map_err(|e| self.error(e))?;
+        self.loaned(request, loan)
+    }
+
+    /// Enables or disables deadlock detection for blocking requests
+    pub fn set_deadlock_detection(&self, enabled : bool) {
+        self.component_collections.set_deadlock_detection(enabled);
+    }
+
+    /// Returns the outstanding loans and the requests waiting on them,
+    /// with every component type replaced by its name
+    pub fn diagnostics(&self) -> Diagnostics<&'static str> {
+        self.named(&self.component_collections.diagnostics())
+    }
+
+    /// Returns the requests and loans involved in a deadlock, if there is one
+    pub fn find_deadlock(&self) -> Option<Diagnostics<&'static str>> {
+        self.component_collections.find_deadlock().map(|deadlock| self.named(&deadlock))
+    }
+
+    /// Replaces the TypeIds of the diagnostics with the names of the types
+    fn named(&self, diagnostics : &Diagnostics<TypeId>) -> Diagnostics<&'static str> {
+        let names = self.names.lock().unwrap();
+        diagnostics.map(|id| names.get(id).cloned().unwrap_or("unknown"))
+    }
+
+    /// Converts an error from the SyncMap
+    fn error(&self, error : SyncMapError<TypeId>) -> ResourceError {
+        match error {
+            SyncMapError::Poisoned => ResourceError::Poisoned,
+            SyncMapError::WouldBlock => ResourceError::WouldBlock,
+            SyncMapError::TimedOut => ResourceError::TimedOut,
+            SyncMapError::Deadlock(deadlock) => ResourceError::Deadlock(self.named(&deadlock)),
+        }
+    }
+
+    /// Registers the Components and EventChannels of the request that are used for the
+    /// first time, singleton resources need a value so they still have to be inserted
+    fn register_missing(&self, request : &ResourceRequest) -> Result<(),ResourceError> {
+        for (id, register) in request.registrars.iter() {
+            if !self.component_collections.contains_key(id).map_err(|_| ResourceError::Poisoned)? {
+                register(self);
+            }
+        }
+        Ok(())
+    }
+
+    /// The SyncMap only returns no loan when a requested type was never registered
+    fn loaned<'a>(&'a self, request : &ResourceRequest, loan : Option<Loan<'a,TypeId,Box<ComponentCollection>>>)
+        -> Result<Loan<'a,TypeId,Box<ComponentCollection>>,ResourceError> {
+        match loan {
+            Some(loan) => Ok(loan),
+            None => {
+                for (id, name) in request.names.iter() {
+                    if !self.component_collections.contains_key(id).map_err(|_| ResourceError::Poisoned)? {
+                        return Err(ResourceError::UnknownType(name));
+                    }
+                }
+                Err(ResourceError::UnknownType("unknown"))
+            }
+        }
+    }
+
+    /// Removes every component belonging to the entity and marks the entity
+    /// as dead. This waits for write access to every collection so it must not
+    /// be called while holding a loan. Returns false if the entity was already dead
+    pub fn delete_entity(&self, entity : Entity) -> bool {
+        if !self.kill(entity) {
+            return false;
+        }
+        self.remove_components(entity);
+        true
+    }
+
+    /// Marks the entity as dead without touching its components, the index
+    /// of the entity can be reused by the next entity that is registered.
+    /// Returns false if the entity was already dead
+    fn kill(&self, entity : Entity) -> bool {
+        self.register.lock().unwrap().kill(entity.id())
+    }
+
+    /// Removes every component belonging to the entity. The storages check the
+    /// generation of the id, so the components of a new entity that reuses
+    /// the index are kept. This waits for write access to every collection
+    pub(crate) fn remove_components(&self, entity : Entity) {
+        let mut request = Request::new();
+        for key in self.component_collections.keys().unwrap() {
+            request.write(key);
+        }
+        let loan = self.component_collections.request(&request).unwrap().unwrap();
+        let tick = self.next_tick();
+        for key in self.component_collections.keys().unwrap() {
+            if let Some(mut collection) = loan.write(&key) {
+                collection.changes_mut().set_tick(tick);
+                collection.remove_entity(entity.id());
+            }
+        }
+    }
+
+    /// Ends the frame, forgetting the removed components that were recorded
+    /// at or before the tick and expiring the events that have been read
+    pub(crate) fn maintain(&self, until : u64) {
+        let mut request = Request::new();
+        for key in self.component_collections.keys().unwrap() {
+            request.write(key);
+        }
+        let loan = self.component_collections.request(&request).unwrap().unwrap();
+        for key in self.component_collections.keys().unwrap() {
+            if let Some(mut collection) = loan.write(&key) {
+                collection.changes_mut().clear_removed(until);
+                collection.maintain();
+            }
+        }
+    }
+
+    /// Returns the current change tick
+    pub fn tick(&self) -> u64 {
+        self.tick.load(Ordering::SeqCst)
     }
 
-    pub fn request(&self, request : &ResourceRequest) -> Loan<TypeId,Box<ComponentCollection>> {
-        self.component_collections.request(&request.request).unwrap().unwrap()
+    /// Advances the change tick, returning the new tick
+    fn next_tick(&self) -> u64 {
+        self.tick.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Returns true if the entity has been registered and not deleted
+    pub fn is_alive(&self, entity : &Entity) -> bool {
+        self.register.lock().unwrap().is_alive(entity.id())
     }
 
     pub(crate) fn get_token(&self) -> ResourceToken<'_>{
         ResourceToken::new(self)
     }
+
+    /// Returns a token that reports the changes made after the tick
+    pub(crate) fn get_token_since(&self, last_run : u64) -> ResourceToken<'_>{
+        ResourceToken::since(self, last_run)
+    }
+
+    /// Returns a token for the system at the index of a dispatcher, the
+    /// commands of the token are applied in the order of the systems
+    pub(crate) fn system_token(&self, index : usize, last_run : u64) -> ResourceToken<'_>{
+        let mut token = ResourceToken::since(self, last_run);
+        token.owner = index;
+        token
+    }
 }
 
 /*************************************************/
@@ -285,33 +810,119 @@ impl Resources {
 pub struct ResourceToken<'a> {
     loan : Option<Loan<'a,TypeId,Box<ComponentCollection>>>,
     resources : &'a Resources,
+    last_run : u64,
+    this_run : u64,
+    owner : usize,
+    commands : RefCell<Commands<'a>>,
 }
 
 impl<'a> ResourceToken<'a>{
     pub fn new(res : &'a Resources) -> ResourceToken<'a> {
+        ResourceToken::since(res, 0)
+    }
+
+    /// Constructs a token that reports the changes made after the tick
+    fn since(res : &'a Resources, last_run : u64) -> ResourceToken<'a> {
         ResourceToken {
             loan : None,
             resources : res,
+            last_run : last_run,
+            this_run : res.tick(),
+            owner : usize::max_value(),
+            commands : RefCell::new(Commands::new(res)),
         }
     }
 
+    /// Returns the command buffer of the token, the commands are applied after the
+    /// token is dropped once the dispatcher finishes or `apply_commands` is called
+    pub fn commands(&self) -> RefMut<Commands<'a>> {
+        self.commands.borrow_mut()
+    }
+
+    /// Returns the tick changes are reported after, for a system this is the
+    /// tick of its last run
+    pub fn last_run(&self) -> u64 {
+        self.last_run
+    }
+
+    /// Returns the tick changes made through the token are stamped with
+    pub fn this_run(&self) -> u64 {
+        self.this_run
+    }
+
     pub fn register<T : Component>(&self) {
         self.resources.register::<T>();
     }
 
+    /// Inserts a singleton resource, this waits for any loan of the resource
+    /// so the token must not hold one
+    pub fn insert_resource<R : Send + Sync + 'static>(&self, resource : R) -> Option<R> {
+        self.resources.insert_resource(resource)
+    }
+
     pub fn register_entity(&self) -> Entity{
-        let id = self.resources.register.lock().unwrap().register(1);
-        Entity::new_with_id(id.start)
+        self.resources.allocate()
     }
 
-    pub fn request(self, request : &ResourceRequest) -> ResourceToken<'a> {
+    /// Starts building a new Entity whose components are inserted into the loaned collections
+    pub fn create_entity(&self) -> EntityBuilder<'_, 'a> {
+        EntityBuilder::new(self)
+    }
+
+    /// Spawns an Entity for every tuple of components, the ids are reserved at once and
+    /// every collection grows a single time. Nothing is spawned if one of the
+    /// Components is not loaned with write permisions
+    pub fn spawn_batch<B : Bundle, I : IntoIterator<Item = B>>(&self, bundles : I) -> Result<Vec<Entity>, UnpackError> {
+        B::check(self)?;
+        let bundles : Vec<B> = bundles.into_iter().collect();
+        let ids = self.resources.allocate_range(bundles.len() as u64);
+        let entities = ids.clone().map(Entity::new_with_id).collect();
+        B::insert(self, ids, bundles);
+        Ok(entities)
+    }
+
+    /// Marks the entity as dead right away, so stale handles to it are detected at once,
+    /// while its components are removed once the commands are applied. The removal is
+    /// queued like `commands().despawn` so the loan held by the token is kept and no other
+    /// collection has to be loaned. Returns false if the entity was already dead
+    pub fn delete_entity(&self, entity : Entity) -> bool {
+        if !self.resources.kill(entity) {
+            return false;
+        }
+        self.commands().remove_components(entity);
+        true
+    }
+
+    pub fn is_alive(&self, entity : &Entity) -> bool {
+        self.resources.is_alive(entity)
+    }
+
+    /// Releases the current loan of the token and replaces it with
+    /// the loan returned by the function
+    fn reloan<F>(self, loan : F) -> Result<ResourceToken<'a>,ResourceError>
+        where F : FnOnce(&'a Resources) -> Result<Loan<'a,TypeId,Box<ComponentCollection>>,ResourceError> {
         let resources = self.resources;
-        //unpack!(self, ResourceRequest, Resources);
+        let mut token = ResourceToken::since(resources, self.last_run);
+        token.owner = self.owner;
         drop(self);
-        ResourceToken {
-            loan : Some(resources.request(request)),
-            resources: resources,
-        }
+        token.loan = Some(loan(resources)?);
+        token.this_run = resources.next_tick();
+        Ok(token)
+    }
+
+    /// Releases the current loan and blocks until the request can be loaned out
+    pub fn request(self, request : &ResourceRequest) -> Result<ResourceToken<'a>,ResourceError> {
+        self.reloan(|resources| resources.request(request))
+    }
+
+    /// Releases the current loan and loans out the request only if it is available right away
+    pub fn try_request(self, request : &ResourceRequest) -> Result<ResourceToken<'a>,ResourceError> {
+        self.reloan(|resources| resources.try_request(request))
+    }
+
+    /// Releases the current loan and waits up to the timeout for the request
+    pub fn request_timeout(self, request : &ResourceRequest, timeout : Duration) -> Result<ResourceToken<'a>,ResourceError> {
+        self.reloan(|resources| resources.request_timeout(request, timeout))
     }
 
     pub fn loan(&self) -> Option<&Loan<'a,TypeId,Box<ComponentCollection>>>{
@@ -321,39 +932,160 @@ impl<'a> ResourceToken<'a>{
         }
     }
 
-    pub fn unpack<C : Component>(&self) -> Option<&Box<ComponentVector<C>>> {
-        match self.loan {
-            Some(ref loan) => {
-                let id = TypeId::of::<C>();
-                match loan.read(&id) {
-                    Some(value) => {
-                        let val = unsafe {
-                            transmute::<&Box<ComponentCollection>, &Box<ComponentVector<C>>>(value)
-                        };
-                        Some(val)
-                    },
-                    None => None,
-                }
-            },
-            None => None,
+    /// Joins the collections of the loan on their entity ids, yielding
+    /// the components of every entity that has all of the required
+    /// components. Returns None if a required collection is not loaned
+    pub fn join<'t, Q : Query<'t>>(&'t self) -> Option<Join<'t, Q>> {
+        Join::new(self)
+    }
+
+    /// Returns the collection stored under the key downcast to T
+    fn borrow<T : Any>(&self, key : TypeId, name : &'static str) -> Result<&T, UnpackError> {
+        let loan = self.loan.as_ref().ok_or(UnpackError::NoLoan)?;
+        let collection = loan.read(&key).ok_or(UnpackError::NotLoaned(name))?;
+        collection.as_any().downcast_ref::<T>().ok_or(UnpackError::WrongType(name))
+    }
+
+    /// Returns the collection stored under the key mutably downcast to T
+    fn borrow_mut<T : Any>(&self, key : TypeId, name : &'static str) -> Result<RefMut<T>, UnpackError> {
+        let loan = self.loan.as_ref().ok_or(UnpackError::NoLoan)?;
+        let mut collection = match loan.write(&key) {
+            Some(collection) => collection,
+            None if loan.read(&key).is_some() => return Err(UnpackError::ReadOnly(name)),
+            None => return Err(UnpackError::NotLoaned(name)),
+        };
+        if !collection.as_any().is::<T>() {
+            return Err(UnpackError::WrongType(name));
         }
+        collection.changes_mut().set_tick(self.this_run);
+        Ok(RefMut::map(collection, |collection| collection.as_any_mut().downcast_mut::<T>().unwrap()))
+    }
+
+    /// Returns the collection of the Component if it was loaned with read permisions
+    pub fn unpack<C : Component>(&self) -> Result<&C::Storage, UnpackError> {
+        self.borrow::<C::Storage>(TypeId::of::<C>(), type_name::<C>())
+    }
+
+    /// Returns the collection of the Component if it was loaned with write permisions
+    /// and is not already unpacked
+    pub fn unpack_mut<C : Component>(&self) -> Result<RefMut<C::Storage>, UnpackError> {
+        self.borrow_mut::<C::Storage>(TypeId::of::<C>(), type_name::<C>())
+    }
+
+    /// Returns the singleton resource if it was loaned with read permisions
+    pub fn unpack_resource<R : Send + Sync + 'static>(&self) -> Result<&R, UnpackError> {
+        self.borrow::<Unique<R>>(TypeId::of::<Unique<R>>(), type_name::<R>()).map(|unique| &unique.value)
     }
 
-    pub fn unpack_mut<C : Component>(&self) -> Option<RefMut<&mut Box<ComponentVector<C>>>> {
-        match self.loan {
-            Some(ref loan) => {
-                let id = TypeId::of::<C>();
-                match loan.write(&id) {
-                    Some(value) => {
-                        let val = unsafe {
-                            transmute::<RefMut<&mut Box<ComponentCollection>>, RefMut<&mut Box<ComponentVector<C>>>>(value)
-                        };
-                        Some(val)
-                    },
-                    None => None,
+    /// Returns the singleton resource if it was loaned with write permisions
+    pub fn unpack_resource_mut<R : Send + Sync + 'static>(&self) -> Result<RefMut<R>, UnpackError> {
+        let unique = self.borrow_mut::<Unique<R>>(TypeId::of::<Unique<R>>(), type_name::<R>())?;
+        Ok(RefMut::map(unique, |unique| &mut unique.value))
+    }
+
+    /// Returns the EventChannel of the event type if it was loaned with read permisions
+    pub fn events<E : Send + Sync + 'static>(&self) -> Result<&EventChannel<E>, UnpackError> {
+        self.borrow::<EventChannel<E>>(TypeId::of::<EventChannel<E>>(), type_name::<EventChannel<E>>())
+    }
+
+    /// Returns the EventChannel of the event type if it was loaned with write permisions
+    pub fn events_mut<E : Send + Sync + 'static>(&self) -> Result<RefMut<EventChannel<E>>, UnpackError> {
+        self.borrow_mut::<EventChannel<E>>(TypeId::of::<EventChannel<E>>(), type_name::<EventChannel<E>>())
+    }
+
+    /// Returns the entity's component, the Component may be loaned with either read or
+    /// write permisions. A write loaned collection stays unpacked while the component is held,
+    /// reading it through a write loan does not mark it as changed.
+    /// Fails if the collection is not loaned or is already unpacked mutably
+    pub fn get<C : Component>(&self, entity : Entity) -> Result<Option<ComponentRef<C>>, UnpackError> {
+        match self.unpack::<C>() {
+            Ok(storage) => Ok(storage.get(entity.id()).map(ComponentRef::Read)),
+            Err(_) => {
+                let storage = self.unpack_mut::<C>()?;
+                if !storage.contains(entity.id()) {
+                    return Ok(None);
                 }
-            },
-            None => None,
+                Ok(Some(ComponentRef::Write(storage, entity.id())))
+            }
+        }
+    }
+
+    /// Returns the entity's component mutably, the Component must be loaned with write permisions
+    pub fn get_mut<C : Component>(&self, entity : Entity) -> Option<RefMut<C>> {
+        let storage = self.unpack_mut::<C>().ok()?;
+        if !storage.contains(entity.id()) {
+            return None;
+        }
+        Some(RefMut::map(storage, |storage| storage.get_mut(entity.id()).unwrap()))
+    }
+
+    /// Returns the entities whose component was removed since the last run, the
+    /// Component may be loaned with either read or write permisions
+    pub fn removed<C : Component>(&self) -> Vec<Entity> {
+        match self.unpack::<C>() {
+            Ok(storage) => storage.changes().removed_since(self.last_run),
+            Err(_) => self.unpack_mut::<C>().map(|storage| storage.changes().removed_since(self.last_run)).unwrap_or_default(),
+        }
+    }
+
+    /// Turns the entity's component back on, returns false if the entity does
+    /// not have the component. The Component must be loaned with write permisions
+    pub fn enable<C : Component>(&self, entity : Entity) -> bool {
+        self.unpack_mut::<C>().map(|mut storage| storage.enable(entity.id())).unwrap_or(false)
+    }
+
+    /// Turns the entity's component off so iterators and joins skip it, returns false
+    /// if the entity does not have the component. The Component must be loaned with write permisions
+    pub fn disable<C : Component>(&self, entity : Entity) -> bool {
+        self.unpack_mut::<C>().map(|mut storage| storage.disable(entity.id())).unwrap_or(false)
+    }
+
+    /// Returns true if the entity has the component and it is turned on, the
+    /// Component may be loaned with either read or write permisions
+    pub fn is_enabled<C : Component>(&self, entity : Entity) -> bool {
+        match self.unpack::<C>() {
+            Ok(storage) => storage.is_enabled(entity.id()),
+            Err(_) => self.unpack_mut::<C>().map(|storage| storage.is_enabled(entity.id())).unwrap_or(false),
+        }
+    }
+
+    /// Returns true if the entity has the component, the Component may be
+    /// loaned with either read or write permisions
+    pub fn has<C : Component>(&self, entity : Entity) -> bool {
+        match self.unpack::<C>() {
+            Ok(storage) => storage.contains(entity.id()),
+            Err(_) => self.unpack_mut::<C>().map(|storage| storage.contains(entity.id())).unwrap_or(false),
+        }
+    }
+}
+
+impl<'a> Drop for ResourceToken<'a> {
+    /// Hands the queued commands to the Resources once the token is done
+    fn drop(&mut self) {
+        let commands = self.commands.get_mut().take();
+        if !commands.is_empty() {
+            self.resources.defer(self.owner, commands);
+        }
+    }
+}
+
+/*************************************************/
+/* A Component borrowed from a read or write loan*/
+/*************************************************/
+/// Returned by `ResourceToken::get`, derefs to the Component
+pub enum ComponentRef<'t, C : Component> {
+    Read(&'t C),
+    /// The unpacked collection and the id of the entity, which is known to hold the Component
+    Write(RefMut<'t, C::Storage>, u64),
+}
+
+impl<'t, C : Component> Deref for ComponentRef<'t, C> {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        match self {
+            ComponentRef::Read(component) => component,
+            ComponentRef::Write(storage, id) => storage.get(*id).unwrap(),
         }
     }
 }
@@ -363,6 +1095,9 @@ impl<'a> ResourceToken<'a>{
 /*************************************************/
 pub struct ResourceRequest {
     request: Request<TypeId>,
+    names: HashMap<TypeId, &'static str>,
+    // registers the collections that can be created without a value
+    registrars: HashMap<TypeId, fn(&Resources)>,
 }
 
 // Wrapper for Request
@@ -370,20 +1105,136 @@ impl ResourceRequest {
     pub fn new() -> ResourceRequest {
         ResourceRequest {
             request : Request::new(),
+            names : HashMap::new(),
+            registrars : HashMap::new(),
         }
     }
 
     pub fn read<T : Component>(&mut self) -> &mut Self {
         let id = TypeId::of::<T>();
         self.request.read(id);
+        self.names.insert(id, type_name::<T>());
+        self.registrars.insert(id, Resources::register::<T>);
         self
     }
 
     pub fn write<T : Component>(&mut self) -> &mut Self {
         let id = TypeId::of::<T>();
         self.request.write(id);
+        self.names.insert(id, type_name::<T>());
+        self.registrars.insert(id, Resources::register::<T>);
+        self
+    }
+
+    /// Requests read access to the singleton resource
+    pub fn read_resource<R : Send + Sync + 'static>(&mut self) -> &mut Self {
+        let id = TypeId::of::<Unique<R>>();
+        self.request.read(id);
+        self.names.insert(id, type_name::<R>());
+        self
+    }
+
+    /// Requests write access to the singleton resource
+    pub fn write_resource<R : Send + Sync + 'static>(&mut self) -> &mut Self {
+        let id = TypeId::of::<Unique<R>>();
+        self.request.write(id);
+        self.names.insert(id, type_name::<R>());
+        self
+    }
+
+    /// Requests read access to the EventChannel of the event type
+    pub fn read_events<E : Send + Sync + 'static>(&mut self) -> &mut Self {
+        let id = TypeId::of::<EventChannel<E>>();
+        self.request.read(id);
+        self.names.insert(id, type_name::<EventChannel<E>>());
+        self.registrars.insert(id, Resources::register_events::<E>);
+        self
+    }
+
+    /// Requests write access to the EventChannel of the event type
+    pub fn write_events<E : Send + Sync + 'static>(&mut self) -> &mut Self {
+        let id = TypeId::of::<EventChannel<E>>();
+        self.request.write(id);
+        self.names.insert(id, type_name::<EventChannel<E>>());
+        self.registrars.insert(id, Resources::register_events::<E>);
         self
     }
+
+    /// Names the owner of the request, the label is shown in the
+    /// diagnostics of the Resources
+    pub fn label(&mut self, label : &str) -> &mut Self {
+        self.request.label(label);
+        self
+    }
+
+    /// Returns true if the two requests can not be loaned out at the same time
+    pub fn conflicts(&self, other : &ResourceRequest) -> bool {
+        self.request.conflicts(&other.request)
+    }
+}
+
+/*************************************************/
+/* Error returned when a request can't be loaned */
+/*************************************************/
+#[derive(Debug, PartialEq, Eq)]
+pub enum ResourceError {
+    /// The singleton resource was requested before it was inserted
+    UnknownType(&'static str),
+    /// A thread panicked while holding the resources
+    Poisoned,
+    /// The resources were not available right away
+    WouldBlock,
+    /// The resources were not available before the timeout
+    TimedOut,
+    /// The request waits on loans that can never be returned
+    Deadlock(Diagnostics<&'static str>),
+}
+impl Display for ResourceError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResourceError::UnknownType(name) => write!(f, "{} was requested before it was registered", name),
+            ResourceError::Poisoned => write!(f, "Resources have been poisioned"),
+            ResourceError::WouldBlock => write!(f, "Resources are loaned out"),
+            ResourceError::TimedOut => write!(f, "Request for Resources timed out"),
+            ResourceError::Deadlock(deadlock) => write!(f, "Request for Resources would deadlock\n{}", deadlock),
+        }
+    }
+}
+impl Error for ResourceError {
+    fn cause(&self) -> Option<&error::Error> {
+        None
+    }
+}
+
+/*************************************************/
+/* Error returned when a collection can't unpack */
+/*************************************************/
+#[derive(Debug, PartialEq, Eq)]
+pub enum UnpackError {
+    /// The token does not hold a loan
+    NoLoan,
+    /// The Component is not part of the loan with the needed permisions,
+    /// or its collection is already unpacked mutably
+    NotLoaned(&'static str),
+    /// The Component is part of the loan but only with read permisions
+    ReadOnly(&'static str),
+    /// The collection stored for the Component is not its Storage type
+    WrongType(&'static str),
+}
+impl Display for UnpackError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UnpackError::NoLoan => write!(f, "Token does not hold a loan"),
+            UnpackError::NotLoaned(name) => write!(f, "{} is not available in the loan", name),
+            UnpackError::ReadOnly(name) => write!(f, "{} is loaned without write permisions", name),
+            UnpackError::WrongType(name) => write!(f, "the collection stored for {} is not its Storage type", name),
+        }
+    }
+}
+impl Error for UnpackError {
+    fn cause(&self) -> Option<&error::Error> {
+        None
+    }
 }
 
 /*************************************************/
@@ -392,6 +1243,7 @@ impl ResourceRequest {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use query::{Read, Write, Added, Changed};
 
     struct CompA {
         id: u64
@@ -415,8 +1267,12 @@ mod tests {
         }
     }
 
-    impl Component for CompA{}
-    impl Component for CompB{}
+    impl Component for CompA{
+        type Storage = ComponentVector<Self>;
+    }
+    impl Component for CompB{
+        type Storage = ComponentVector<Self>;
+    }
 
     #[test]
     fn test_basic_component_vector() {
@@ -428,20 +1284,140 @@ mod tests {
         let _ = cv.push(b, 1);
         let _ = cv.push(c, 2);
         let mut i = 0;
-        for item in cv.iter() {
+        for item in cv.list() {
             assert!(item.get_entity() == item.component.id);
             i += 1;
         }
         assert!(i == 3);
         cv.remove(1);
         i = 0;
-        for item in cv.iter(){
+        for item in cv.list(){
             assert!(item.get_entity() == item.component.id);
             i+=1;
         }
         assert!(i == 2);
     }
 
+    #[test]
+    fn test_component_vector_iter() {
+        let mut cv : ComponentVector<CompA> = ComponentVector::new();
+        for id in 0..4 {
+            let _ = cv.push(CompA::new(id), id);
+        }
+        cv.remove(1);
+
+        for (entity, comp) in cv.iter_mut() {
+            comp.id += entity.id() * 10;
+        }
+        let items : Vec<(u64, u64)> = cv.iter().map(|(entity, comp)| (entity.id(), comp.id)).collect();
+        assert!(items == vec!((0, 0), (2, 22), (3, 33)));
+        let entities : Vec<u64> = cv.entities().map(|entity| entity.id()).collect();
+        assert!(entities == vec!(0, 2, 3));
+
+        let mut empty : ComponentVector<CompA> = ComponentVector::new();
+        assert!(empty.iter().next().is_none() && empty.iter_mut().next().is_none());
+    }
+
+    #[test]
+    fn test_enable_disable() {
+        let resources = Resources::new();
+        resources.register::<CompA>();
+        resources.register::<CompB>();
+
+        let mut request = ResourceRequest::new();
+        request.write::<CompA>().write::<CompB>();
+        let token = resources.get_token().request(&request).unwrap();
+        let entities : Vec<Entity> = (0..3).map(|id| token.register_entity()
+            .with(CompA::new(id), token.unpack_mut::<CompA>().unwrap())
+            .with(CompB::new(id), token.unpack_mut::<CompB>().unwrap())).collect();
+        let missing = token.register_entity();
+
+        assert!(token.disable::<CompA>(entities[1]));
+        assert!(!token.disable::<CompA>(missing));
+        assert!(!token.is_enabled::<CompA>(entities[1]) && token.is_enabled::<CompA>(entities[0]));
+        // disabled components are still there
+        assert!(token.has::<CompA>(entities[1]));
+
+        {
+            let mut comp_a = token.unpack_mut::<CompA>().unwrap();
+            for (_, comp) in comp_a.iter_mut() {
+                comp.id += 10;
+            }
+            let ids : Vec<u64> = comp_a.iter().map(|(_, comp)| comp.id).collect();
+            assert!(ids == vec!(10, 12));
+            let ids : Vec<u64> = comp_a.iter_all().map(|(_, comp)| comp.id).collect();
+            assert!(ids == vec!(10, 1, 12));
+            assert!(comp_a.entities().count() == 2 && comp_a.par_iter().count() == 2);
+        }
+
+        // joins skip the entity unless the component is optional
+        let joined : Vec<u64> = token.join::<(Write<CompA>, Write<CompB>)>().unwrap().iter_mut()
+            .map(|(entity, _)| entity.id()).collect();
+        assert!(joined == vec!(0, 2));
+        let joined : Vec<bool> = token.join::<(Write<CompB>, Option<Write<CompA>>)>().unwrap().iter_mut()
+            .map(|(_, (_, comp_a))| comp_a.is_some()).collect();
+        assert!(joined == vec!(true, false, true));
+
+        assert!(token.enable::<CompA>(entities[1]));
+        assert!(token.join::<(Write<CompA>, Write<CompB>)>().unwrap().iter_mut().count() == 3);
+    }
+
+    #[test]
+    fn test_change_ticks() {
+        let resources = Resources::new();
+        resources.register::<CompA>();
+        let mut writer = ResourceRequest::new();
+        writer.write::<CompA>();
+        let mut reader = ResourceRequest::new();
+        reader.read::<CompA>();
+
+        let token = resources.get_token_since(0).request(&writer).unwrap();
+        let entities : Vec<Entity> = (0..3).map(|id| token.register_entity()
+            .with(CompA::new(id), token.unpack_mut::<CompA>().unwrap())).collect();
+        drop(token);
+        let last_run = resources.tick();
+
+        // nothing happened since the last run
+        let token = resources.get_token_since(last_run).request(&reader).unwrap();
+        assert!(token.join::<(Changed<CompA>,)>().unwrap().iter_mut().count() == 0);
+        drop(token);
+        // a fresh token reports everything
+        let token = resources.get_token().request(&reader).unwrap();
+        assert!(token.join::<(Added<CompA>,)>().unwrap().iter_mut().count() == 3);
+
+        let token = token.request(&writer).unwrap();
+        token.get_mut::<CompA>(entities[1]).unwrap().id = 10;
+        let added = token.register_entity().with(CompA::new(3), token.unpack_mut::<CompA>().unwrap());
+        assert!(token.unpack_mut::<CompA>().unwrap().remove(entities[0].id()).is_some());
+        drop(token);
+
+        let token = resources.get_token_since(last_run).request(&reader).unwrap();
+        let ids = |entities : Vec<Entity>| -> Vec<u64> { entities.iter().map(|entity| entity.id()).collect() };
+        let added_since : Vec<Entity> = token.join::<(Added<CompA>,)>().unwrap().iter_mut().map(|(entity, _)| entity).collect();
+        assert!(ids(added_since) == vec!(added.id()));
+        let changed_since : Vec<Entity> = token.join::<(Changed<CompA>,)>().unwrap().iter_mut().map(|(entity, _)| entity).collect();
+        assert!(ids(changed_since) == vec!(entities[1].id(), added.id()));
+        assert!(ids(token.removed::<CompA>()) == vec!(entities[0].id()));
+        drop(token);
+
+        // removals are forgotten once every system has seen them
+        resources.maintain(resources.tick());
+        let token = resources.get_token_since(last_run).request(&reader).unwrap();
+        assert!(token.removed::<CompA>().is_empty());
+    }
+
+    #[test]
+    fn test_component_vector_par_iter() {
+        let mut cv : ComponentVector<CompA> = ComponentVector::new();
+        for id in 0..1000 {
+            let _ = cv.push(CompA::new(0), id);
+        }
+        cv.par_iter_mut().for_each(|(entity, comp)| comp.id = entity.id() * 2);
+        let sum : u64 = cv.par_iter().map(|(_, comp)| comp.id).sum();
+        assert!(sum == 999 * 1000);
+        assert!(cv.par_iter().all(|(entity, comp)| comp.id == entity.id() * 2));
+    }
+
     #[test]
     fn test_adv_component_vector(){
         let a = CompA::new(0);
@@ -462,7 +1438,7 @@ mod tests {
         // Insert 5 elements
         let actual : Vec<u64> = [0, 1, 2, 3, 4, 5].iter().map(|d| *d as u64).collect();
         let order : Vec<u64>  = [0, 1, 2, 3, 4, 5].iter().map(|d| *d as u64).collect();
-        let cv_order : Vec<u64> = cv.iter().map(|c| c.get_entity()).collect();
+        let cv_order : Vec<u64> = cv.list().map(|c| c.get_entity()).collect();
         let cv_actual : Vec<u64> = cv.components.iter().map(|c| c.get_entity()).collect();
         assert!(actual == cv_actual);
         assert!(order == cv_order);
@@ -471,7 +1447,7 @@ mod tests {
         cv.remove(2);
         let actual : Vec<u64> = [0, 1, 5, 3, 4].iter().map(|d| *d as u64).collect();
         let order : Vec<u64>  = [0, 1, 3, 4, 5].iter().map(|d| *d as u64).collect();
-        let cv_order : Vec<u64> = cv.iter().map(|c| c.get_entity()).collect();
+        let cv_order : Vec<u64> = cv.list().map(|c| c.get_entity()).collect();
         let cv_actual : Vec<u64> = cv.components.iter().map(|c| c.get_entity()).collect();
         assert!(actual == cv_actual);
         assert!(order == cv_order);
@@ -480,7 +1456,7 @@ mod tests {
         cv.remove(4);
         let actual : Vec<u64> = [0, 1, 5, 3].iter().map(|d| *d as u64).collect();
         let order : Vec<u64>  = [0, 1, 3, 5].iter().map(|d| *d as u64).collect();
-        let cv_order : Vec<u64> = cv.iter().map(|c| c.get_entity()).collect();
+        let cv_order : Vec<u64> = cv.list().map(|c| c.get_entity()).collect();
         let cv_actual : Vec<u64> = cv.components.iter().map(|c| c.get_entity()).collect();
         assert!(actual == cv_actual);
         assert!(order == cv_order);
@@ -489,7 +1465,7 @@ mod tests {
         cv.remove(0);
         let actual : Vec<u64> = [3, 1, 5].iter().map(|d| *d as u64).collect();
         let order : Vec<u64>  = [1, 3, 5].iter().map(|d| *d as u64).collect();
-        let cv_order : Vec<u64> = cv.iter().map(|c| c.get_entity()).collect();
+        let cv_order : Vec<u64> = cv.list().map(|c| c.get_entity()).collect();
         let cv_actual : Vec<u64> = cv.components.iter().map(|c| c.get_entity()).collect();
         assert!(actual == cv_actual);
         assert!(order == cv_order);
@@ -498,12 +1474,309 @@ mod tests {
         let _ = cv.push(g, 6);
         let actual : Vec<u64> = [3, 1, 5, 6].iter().map(|d| *d as u64).collect();
         let order : Vec<u64>  = [1, 3, 5, 6].iter().map(|d| *d as u64).collect();
-        let cv_order : Vec<u64> = cv.iter().map(|c| c.get_entity()).collect();
+        let cv_order : Vec<u64> = cv.list().map(|c| c.get_entity()).collect();
         let cv_actual : Vec<u64> = cv.components.iter().map(|c| c.get_entity()).collect();
         assert!(actual == cv_actual);
         assert!(order == cv_order);
     }
 
+    #[test]
+    fn test_component_vector_lookup(){
+        let mut cv : ComponentVector<CompA> = ComponentVector::new();
+        for id in 0..10 {
+            cv.push(CompA::new(id), id);
+        }
+        assert!(cv.contains(4));
+        assert!(cv.get(4).unwrap().id == 4);
+        cv.get_mut(4).unwrap().id = 40;
+        assert!(cv.get(4).unwrap().id == 40);
+
+        // pushing an entity a second time replaces the component in place
+        cv.push(CompA::new(44), 4);
+        assert!(cv.len() == 10);
+        assert!(cv.get(4).unwrap().id == 44);
+
+        assert!(cv.remove(4).unwrap().id == 44);
+        assert!(!cv.contains(4));
+        assert!(cv.get(4).is_none());
+        assert!(cv.remove(4).is_none());
+        assert!(cv.remove(100).is_none());
+    }
+
+    #[test]
+    fn test_component_vector_remove_order(){
+        // Remove in a scattered order and check that the remaining
+        // components are still reachable and in insertion order
+        let mut cv : ComponentVector<CompA> = ComponentVector::new();
+        let mut expected : Vec<u64> = (0..50).collect();
+        for id in expected.iter() {
+            cv.push(CompA::new(*id), *id);
+        }
+        for step in 0..40 {
+            let id = expected[(step * 7) % expected.len()];
+            expected.retain(|e| *e != id);
+            assert!(cv.remove(id).unwrap().id == id);
+            let order : Vec<u64> = cv.list().map(|c| c.get_entity()).collect();
+            assert!(order == expected);
+            for id in expected.iter() {
+                assert!(cv.get(*id).unwrap().id == *id);
+            }
+        }
+        cv.push(CompA::new(100), 100);
+        expected.push(100);
+        let order : Vec<u64> = cv.list().map(|c| c.get_entity()).collect();
+        assert!(order == expected);
+    }
+
+    #[test]
+    fn test_delete_entity(){
+        let resources = Resources::new();
+        resources.register::<CompA>();
+        resources.register::<CompB>();
+
+        let mut request = ResourceRequest::new();
+        request.write::<CompA>().write::<CompB>();
+        let token = resources.get_token().request(&request).unwrap();
+        let first = token.register_entity()
+            .with(CompA::new(0), token.unpack_mut::<CompA>().unwrap())
+            .with(CompB::new(0), token.unpack_mut::<CompB>().unwrap());
+        let second = token.register_entity()
+            .with(CompA::new(1), token.unpack_mut::<CompA>().unwrap());
+        assert!(token.is_alive(&first));
+
+        // the entity dies right away, its components are removed once the commands are applied
+        assert!(token.delete_entity(first));
+        assert!(token.loan().is_some());
+        assert!(!token.is_alive(&first));
+        assert!(!token.delete_entity(first));
+        assert!(token.unpack_mut::<CompA>().unwrap().contains(first.id()));
+
+        // the index of the deleted entity is recycled with a new generation
+        let third = token.register_entity()
+            .with(CompA::new(2), token.unpack_mut::<CompA>().unwrap());
+        assert!(third.index() == first.index());
+        assert!(third.generation() == first.generation() + 1);
+        drop(token);
+        resources.apply_commands();
+        let token = resources.get_token();
+        assert!(!token.is_alive(&first));
+        assert!(token.is_alive(&second) && token.is_alive(&third));
+        assert!(!resources.delete_entity(first));
+
+        // the removal leaves the components of the recycled index alone
+        let token = token.request(&request).unwrap();
+        let comp_a = token.unpack_mut::<CompA>().unwrap();
+        let comp_b = token.unpack_mut::<CompB>().unwrap();
+        assert!(comp_a.get(first.id()).is_none());
+        assert!(!comp_b.contains(first.id()));
+        assert!(comp_a.get(second.id()).unwrap().id == 1);
+        assert!(comp_a.get(third.id()).unwrap().id == 2);
+    }
+
+    #[test]
+    fn test_request_errors(){
+        let resources = Resources::new();
+        resources.register::<CompA>();
+
+        // CompB is registered the first time it is requested
+        let mut request = ResourceRequest::new();
+        request.read::<CompA>().read::<CompB>();
+        assert!(!resources.contains::<CompB>());
+        assert!(resources.get_token().request(&request).unwrap().unpack::<CompB>().unwrap().len() == 0);
+        assert!(resources.contains::<CompB>());
+
+        let mut request = ResourceRequest::new();
+        request.write::<CompA>();
+        let token = resources.get_token().request(&request).unwrap();
+        assert!(resources.get_token().try_request(&request).err() == Some(ResourceError::WouldBlock));
+        let timeout = Duration::from_millis(10);
+        assert!(resources.get_token().request_timeout(&request, timeout).err() == Some(ResourceError::TimedOut));
+
+        // registering on first request does not wait on the loans that are out
+        let mut events = ResourceRequest::new();
+        events.write_events::<u32>();
+        assert!(resources.get_token().try_request(&events).is_ok());
+
+        // requesting through the same token releases its loan first
+        assert!(token.try_request(&request).is_ok());
+    }
+
+    #[test]
+    fn test_unpack_errors(){
+        let resources = Resources::new();
+        resources.register::<CompA>();
+        resources.register::<CompB>();
+        assert!(resources.get_token().unpack::<CompA>().err() == Some(UnpackError::NoLoan));
+
+        let mut request = ResourceRequest::new();
+        request.read::<CompA>().write::<CompB>();
+        let token = resources.get_token().request(&request).unwrap();
+        assert!(token.unpack::<CompA>().is_ok());
+        match token.unpack_mut::<CompA>() {
+            Err(UnpackError::ReadOnly(name)) => assert!(name.ends_with("CompA")),
+            _ => panic!("CompA was only loaned for reading"),
+        }
+
+        // CompB can only be unpacked mutably once at a time
+        let comp_b = token.unpack_mut::<CompB>().unwrap();
+        assert!(token.unpack_mut::<CompB>().is_err());
+        drop(comp_b);
+        assert!(token.unpack_mut::<CompB>().is_ok());
+    }
+
+    #[test]
+    fn test_entity_access(){
+        let resources = Resources::new();
+        resources.register::<CompA>();
+        resources.register::<CompB>();
+
+        let mut request = ResourceRequest::new();
+        request.write::<CompA>().write::<CompB>();
+        let token = resources.get_token().request(&request).unwrap();
+        let both = token.register_entity()
+            .with(CompA::new(1), token.unpack_mut::<CompA>().unwrap())
+            .with(CompB::new(2), token.unpack_mut::<CompB>().unwrap());
+        let only_a = token.register_entity()
+            .with(CompA::new(3), token.unpack_mut::<CompA>().unwrap());
+
+        assert!(token.has::<CompB>(both) && !token.has::<CompB>(only_a));
+        token.get_mut::<CompA>(only_a).unwrap().id += 10;
+        assert!(token.get_mut::<CompB>(only_a).is_none());
+        // get works with write permisions as well and keeps the collection unpacked
+        let comp_a = token.get::<CompA>(only_a).unwrap().unwrap();
+        assert!(comp_a.id == 13);
+        assert!(token.unpack_mut::<CompA>().is_err());
+        // a busy collection is not mistaken for a missing component
+        assert!(token.get::<CompA>(only_a).is_err());
+        drop(comp_a);
+        assert!(token.get::<CompB>(only_a).unwrap().is_none());
+
+        let mut request = ResourceRequest::new();
+        request.read::<CompA>().read::<CompB>();
+        let token = token.request(&request).unwrap();
+        assert!(token.get::<CompA>(only_a).unwrap().unwrap().id == 13);
+        assert!(token.get::<CompB>(both).unwrap().unwrap().id == 2);
+        assert!(token.get::<CompB>(only_a).unwrap().is_none());
+        assert!(token.has::<CompA>(both));
+        assert!(token.get_mut::<CompA>(both).is_none());
+
+        // the unpacked collections can be asked directly
+        let comp_a = token.unpack::<CompA>().unwrap();
+        assert!(comp_a.contains(both.id()) && comp_a.get(both.id()).unwrap().id == 1);
+
+        // deleted entities no longer have components
+        token.delete_entity(both);
+        drop(token);
+        resources.apply_commands();
+        let token = resources.get_token().request(&request).unwrap();
+        assert!(!token.has::<CompA>(both) && token.get::<CompA>(both).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_get_unchanged(){
+        let resources = Resources::new();
+        resources.register::<CompA>();
+
+        let mut request = ResourceRequest::new();
+        request.write::<CompA>();
+        let token = resources.get_token().request(&request).unwrap();
+        let entity = token.register_entity()
+            .with(CompA::new(1), token.unpack_mut::<CompA>().unwrap());
+        let since = token.this_run();
+        drop(token);
+
+        // reading through a write loan leaves the component unchanged
+        let token = resources.get_token().request(&request).unwrap();
+        assert!(token.get::<CompA>(entity).unwrap().unwrap().id == 1);
+        assert!(!token.unpack_mut::<CompA>().unwrap().is_changed(entity.id(), since));
+        token.get_mut::<CompA>(entity).unwrap().id += 1;
+        assert!(token.unpack_mut::<CompA>().unwrap().is_changed(entity.id(), since));
+    }
+
+    #[test]
+    fn test_unpack_wrong_type(){
+        let resources = Resources::new();
+        // store a collection that is not the Storage type of CompA
+        resources.component_collections.insert(TypeId::of::<CompA>(), Box::new(ComponentVector::<CompB>::new())).unwrap();
+
+        let mut request = ResourceRequest::new();
+        request.write::<CompA>();
+        let token = resources.get_token().request(&request).unwrap();
+        match token.unpack_mut::<CompA>() {
+            Err(UnpackError::WrongType(name)) => assert!(name.ends_with("CompA")),
+            _ => panic!("the collection should not downcast to CompA's Storage"),
+        }
+
+        let mut request = ResourceRequest::new();
+        request.read::<CompA>();
+        let token = token.request(&request).unwrap();
+        assert!(token.unpack::<CompA>().is_err());
+        assert!(token.join::<(Read<CompA>,)>().is_none());
+    }
+
+    struct Gravity(f32);
+
+    #[test]
+    fn test_singleton_resources(){
+        let resources = Resources::new();
+        resources.register::<CompA>();
+        assert!(resources.insert_resource(Gravity(9.8)).is_none());
+
+        let mut request = ResourceRequest::new();
+        request.write_resource::<Gravity>().read::<CompA>();
+        let token = resources.get_token().request(&request).unwrap();
+        assert!(token.unpack_resource::<Gravity>().is_err());
+        token.unpack_resource_mut::<Gravity>().unwrap().0 *= 2.0;
+        // a singleton does not share a key with a component of the same type
+        assert!(token.unpack_resource::<CompA>().is_err());
+
+        // readers and writers of a resource conflict like collections
+        let mut reader = ResourceRequest::new();
+        reader.read_resource::<Gravity>();
+        assert!(resources.get_token().try_request(&reader).err() == Some(ResourceError::WouldBlock));
+        assert!(request.conflicts(&reader));
+        drop(token);
+
+        let token = resources.get_token().request(&reader).unwrap();
+        assert!(token.unpack_resource::<Gravity>().unwrap().0 == 19.6);
+        drop(token);
+
+        assert!(resources.insert_resource(Gravity(1.0)).unwrap().0 == 19.6);
+        let mut missing = ResourceRequest::new();
+        missing.read_resource::<String>();
+        match resources.get_token().request(&missing) {
+            Err(ResourceError::UnknownType(name)) => assert!(name.ends_with("String")),
+            _ => panic!("String was never inserted"),
+        };
+    }
+
+    #[test]
+    fn test_deadlock_diagnostics(){
+        let resources = Resources::new();
+        resources.register::<CompA>();
+        resources.set_deadlock_detection(true);
+
+        let mut request = ResourceRequest::new();
+        request.write::<CompA>().label("writer");
+        let token = resources.get_token().request(&request).unwrap();
+        assert!(resources.diagnostics().loans[0].writes[0].ends_with("CompA"));
+
+        // a second token on the same thread can never be loaned CompA
+        let mut reader = ResourceRequest::new();
+        reader.read::<CompA>().label("reader");
+        match resources.get_token().request(&reader) {
+            Err(ResourceError::Deadlock(deadlock)) => {
+                assert!(deadlock.loans[0].label == Some("writer".to_string()));
+                assert!(deadlock.waiting[0].reads[0].ends_with("CompA"));
+            },
+            _ => panic!("the request should have deadlocked"),
+        }
+        assert!(resources.find_deadlock().is_none());
+
+        drop(token);
+        assert!(resources.get_token().request(&reader).is_ok());
+    }
+
     #[test]
     fn test_res(){
        
@@ -1,7 +1,9 @@
-use dispatcher::Dispatcher;
-use systems::System;
+use dispatcher::{Dispatcher, DispatchMode, OrderError};
+use systems::{System, SystemOrder};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use resources::Resources;
+use time::Time;
 
 /*************************************************/
 /* Valid State Transitions                       */
@@ -11,6 +13,11 @@ pub enum Trans {
     Pop,
     Push(State),
     Swap(State),
+    /// Exits every state on the stack, top first, and stops the State Machine
+    Quit,
+    /// Exits every state on the stack, top first, then pushes the states
+    /// in order so the last one becomes the active state
+    Replace(Vec<State>),
 }
 
 /*************************************************/
@@ -18,6 +25,8 @@ pub enum Trans {
 /*************************************************/
 pub struct State {
     dispatcher: Dispatcher,
+    fixed: Dispatcher,
+    shadow: Dispatcher,
 }
 
 impl State {
@@ -25,6 +34,8 @@ impl State {
     pub fn new() -> State {
         State {
             dispatcher: Dispatcher::new(),
+            fixed: Dispatcher::new(),
+            shadow: Dispatcher::new(),
         }
     }
 
@@ -34,30 +45,102 @@ impl State {
         self
     }
 
-    /// signals the dispatcher to call the on_start functions
+    /// Adds a new system to the states dispatcher that is ordered
+    /// against the other systems of the state
+    pub fn with_ordered(mut self, system : Box<System>, order : SystemOrder) -> State {
+        self.dispatcher.with_ordered(system, order);
+        self
+    }
+
+    /// Adds a system that is updated at the fixed timestep of the StateMachine,
+    /// zero or more times a frame before the systems added with `with`
+    pub fn with_fixed(mut self, system : Box<System>) -> State {
+        self.fixed.with(system);
+        self
+    }
+
+    /// Adds a system that keeps being updated while the state is paused under
+    /// other states, such as the rendering of a world under a pause menu.
+    /// Shadow systems are started and exited along with the state, the
+    /// transitions they return are ignored
+    pub fn with_shadow(mut self, system : Box<System>) -> State {
+        self.shadow.with(system);
+        self
+    }
+
+    /// Sets how the systems of the state are run, Sequential runs them
+    /// one at a time in order on the State Machines thread
+    pub fn with_mode(mut self, mode : DispatchMode) -> State {
+        self.dispatcher.set_mode(mode);
+        self.fixed.set_mode(mode);
+        self.shadow.set_mode(mode);
+        self
+    }
+
+    /// Resolves the order of the systems, returns an error if the
+    /// constraints of the systems can not be satisfied
+    pub fn build(mut self) -> Result<State, OrderError> {
+        self.dispatcher.build()?;
+        self.fixed.build()?;
+        self.shadow.build()?;
+        Ok(self)
+    }
+
+    /// signals the dispatchers to call the on_start functions
     pub fn on_start(&mut self, resources : Arc<Resources>) {
-        self.dispatcher.on_start(resources);
+        self.dispatcher.on_start(resources.clone());
+        self.fixed.on_start(resources.clone());
+        self.shadow.on_start(resources);
     }
 
-    /// signals the dispatcher to call the on_exit functions
+    /// signals the dispatchers to call the on_exit functions
     pub fn on_exit(&mut self, resources : Arc<Resources>) {
-        self.dispatcher.on_exit(resources);
+        self.dispatcher.on_exit(resources.clone());
+        self.fixed.on_exit(resources.clone());
+        self.shadow.on_exit(resources);
     }
 
-    /// signals the dispatcher to call the on_pause functions
+    /// signals the dispatchers to call the on_pause functions, the shadow
+    /// systems start out having seen every change made so far
     pub fn on_pause(&mut self, resources : Arc<Resources>) {
-        self.dispatcher.on_pause(resources);
+        self.dispatcher.on_pause(resources.clone());
+        self.fixed.on_pause(resources.clone());
+        self.shadow.set_last_runs(resources.tick());
     }
 
-    /// signals the dispatcher to call the on_resume functions
+    /// signals the dispatchers to call the on_resume functions
     pub fn on_resume(&mut self, resources : Arc<Resources>) {
-        self.dispatcher.on_resume(resources);
+        self.dispatcher.on_resume(resources.clone());
+        self.fixed.on_resume(resources);
     }
 
     /// signals the dispatcher to call the on_update functions
     pub fn on_update(&mut self, resources : Arc<Resources>) -> Trans {
        self.dispatcher.on_update(resources)
     }
+
+    /// signals the fixed dispatcher to call the on_update functions,
+    /// this is called once for every fixed step
+    pub fn on_fixed_update(&mut self, resources : Arc<Resources>) -> Trans {
+        self.fixed.on_update(resources)
+    }
+
+    /// Returns the tick of the system of the state that ran the longest time ago,
+    /// the shadow systems only count while the state is paused since they don't
+    /// run while the state is active
+    pub fn oldest_run(&self, paused : bool) -> Option<u64> {
+        let shadow = if paused { self.shadow.oldest_run() } else { None };
+        vec!(self.dispatcher.oldest_run(), self.fixed.oldest_run(), shadow)
+            .into_iter()
+            .filter_map(|run| run)
+            .min()
+    }
+
+    /// signals the shadow dispatcher to call the on_update functions,
+    /// this is called every frame while the state is paused
+    pub fn on_shadow_update(&mut self, resources : Arc<Resources>) {
+        self.shadow.on_update(resources);
+    }
 }
 
 /// Returns the update Status of the StateMachine
@@ -72,63 +155,531 @@ pub enum UpdateStatus {
 pub struct StateMachine {
     stack: Vec<State>,
     resources: Arc<Resources>,
+    time: Time,
+    max_frame_time: Duration,
+    accumulator: Duration,
 }
 
 impl StateMachine {
-    /// Creates a new statemachine
+    /// Creates a new statemachine that runs fixed steps 60 times a second
+    /// and clamps frames to a quarter of a second
     pub fn new(initial_state : State) -> StateMachine {
+        let resources = Arc::new(Resources::new());
+        let time = Time::new(Duration::from_nanos(1_000_000_000 / 60));
+        resources.insert_resource(time);
         StateMachine {
             stack: vec!(initial_state),
-            resources: Arc::new(Resources::new()),
+            resources: resources,
+            time: time,
+            max_frame_time: Duration::from_millis(250),
+            accumulator: Duration::from_secs(0),
+        }
+    }
+
+    /// Sets the time every fixed step covers
+    pub fn with_fixed_timestep(mut self, step : Duration) -> StateMachine {
+        assert!(step > Duration::from_secs(0), "the fixed timestep must be longer than zero");
+        self.time = Time::new(step);
+        self.resources.insert_resource(self.time);
+        self
+    }
+
+    /// Sets the longest time a single frame can cover, slow frames are clamped
+    /// so the fixed steps of a frame can't keep falling further behind
+    pub fn with_max_frame_time(mut self, max : Duration) -> StateMachine {
+        self.max_frame_time = max;
+        self
+    }
+
+    /// Advances the StateMachine by a frame that took the frame time. The top state
+    /// runs a fixed step for every fixed timestep that fits into the time
+    /// accumulated so far, then the frame is updated once
+    pub fn advance(&mut self, frame_time : Duration) -> UpdateStatus {
+        let frame_time = frame_time.min(self.max_frame_time);
+        self.accumulator += frame_time;
+        self.time.start_frame(frame_time);
+        while self.accumulator >= self.time.fixed_delta() {
+            self.accumulator -= self.time.fixed_delta();
+            self.time.start_fixed_step();
+            self.resources.insert_resource(self.time);
+            // a fixed step emptied the stack, the frame still ends as usual
+            let transition = match self.stack.last_mut() {
+                Some(state) => state.on_fixed_update(self.resources.clone()),
+                None => break,
+            };
+            self.transition(transition);
         }
+        self.time.set_remainder(self.accumulator);
+        self.resources.insert_resource(self.time);
+        let status = self.update();
+        self.maintain();
+        status
+    }
+
+    /// Ends the frame by applying the queued commands. Removals are kept until every
+    /// system of every state on the stack has seen them, so paused states still see
+    /// them once they resume. Events expire once a frame however many fixed steps ran
+    fn maintain(&mut self) {
+        self.resources.apply_commands();
+        let top = self.stack.len().saturating_sub(1);
+        let seen = self.stack.iter()
+            .enumerate()
+            .filter_map(|(index, state)| state.oldest_run(index != top))
+            .min()
+            .unwrap_or_else(|| self.resources.tick());
+        self.resources.maintain(seen);
     }
 
-    /// Perfroms a single update on the StateMachine
+    /// Perfroms a single update on the StateMachine. The shadow systems of the
+    /// paused states are updated from the bottom of the stack up, followed
+    /// by the state on top of the stack
     fn update(&mut self) -> UpdateStatus {
+        let transition = {
+            let (active, paused) = match self.stack.split_last_mut() {
+                Some(split) => split,
+                None => return UpdateStatus::Exit,
+            };
+            for state in paused.iter_mut() {
+                state.on_shadow_update(self.resources.clone());
+            }
+            active.on_update(self.resources.clone())
+        };
+        self.transition(transition);
         match self.stack.len() {
             0 => UpdateStatus::Exit,
-            _ => {
-                match self.stack[0].on_update(self.resources.clone()) {
-                    Trans::None => UpdateStatus::Continue,
-                    Trans::Pop => {
-                        self.stack[0].on_exit(self.resources.clone());
-                        self.stack.pop();
-                        match self.stack.first_mut() {
-                            Some(new_state) => {
-                                new_state.on_resume(self.resources.clone());
-                                UpdateStatus::Continue
-                            }
-                            None => UpdateStatus::Exit
-                        } 
-                    }
-                    Trans::Push(mut new_state) => {
-                        self.stack[0].on_pause(self.resources.clone());
-                        new_state.on_start(self.resources.clone());
-                        self.stack.push(new_state);
-                        UpdateStatus::Continue
-                    }
-                    Trans::Swap(mut new_state) => {
-                        self.stack[0].on_exit(self.resources.clone());
-                        self.stack.pop();
-                        new_state.on_start(self.resources.clone());
-                        self.stack.push(new_state);
-                        UpdateStatus::Continue
-                    }
+            _ => UpdateStatus::Continue,
+        }
+    }
+
+    /// Applies the transition returned by the state on top of the stack
+    fn transition(&mut self, transition : Trans) {
+        match transition {
+            Trans::None => {}
+            Trans::Pop => {
+                self.pop();
+                if let Some(state) = self.stack.last_mut() {
+                    state.on_resume(self.resources.clone());
+                }
+            }
+            Trans::Push(state) => self.push(state),
+            Trans::Swap(mut state) => {
+                // the state below stays paused
+                self.pop();
+                state.on_start(self.resources.clone());
+                self.stack.push(state);
+            }
+            Trans::Quit => self.clear(),
+            Trans::Replace(states) => {
+                self.clear();
+                for state in states {
+                    self.push(state);
                 }
             }
         }
     }
 
-    /// Runs the StateMachine until it finishes
+    /// Pauses the active state and starts the new state on top of it
+    fn push(&mut self, mut state : State) {
+        if let Some(paused) = self.stack.last_mut() {
+            paused.on_pause(self.resources.clone());
+        }
+        state.on_start(self.resources.clone());
+        self.stack.push(state);
+    }
+
+    /// Exits the active state and removes it from the stack
+    fn pop(&mut self) {
+        if let Some(mut state) = self.stack.pop() {
+            state.on_exit(self.resources.clone());
+        }
+    }
+
+    /// Exits every state from the top of the stack down
+    fn clear(&mut self) {
+        while !self.stack.is_empty() {
+            self.pop();
+        }
+    }
+
+    /// Runs the StateMachine until it finishes, every frame is
+    /// advanced by the time the previous frame took
     pub fn run(&mut self) {
-        if self.stack.len() > 0 {
-            self.stack[0].on_start(self.resources.clone());
+        if let Some(state) = self.stack.last_mut() {
+            state.on_start(self.resources.clone());
         }
+        let mut last = Instant::now();
         loop {
-            match self.update() {
+            let now = Instant::now();
+            let frame_time = now - last;
+            last = now;
+            match self.advance(frame_time) {
                 UpdateStatus::Continue => continue,
                 UpdateStatus::Exit => break,
             }
-        } 
+        }
     }
-}
\ No newline at end of file
+}
+
+/*************************************************/
+/* Unit Tests                                    */
+/*************************************************/
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use resources::{ResourceToken, ResourceRequest, Component, ComponentCollection, ComponentVector};
+    use entity::Entity;
+    use storage::Storage;
+    use events::EventChannel;
+
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    /// Logs every lifecycle call and returns the scripted transitions from update
+    struct Lifecycle {
+        name : &'static str,
+        log : Log,
+        script : Arc<Mutex<Vec<Trans>>>,
+    }
+    impl Lifecycle {
+        fn record(&self, event : &str) {
+            self.log.lock().unwrap().push(format!("{} {}", self.name, event));
+        }
+    }
+    impl System for Lifecycle {
+        fn update(&mut self, _token : ResourceToken) -> Trans {
+            self.record("update");
+            let mut script = self.script.lock().unwrap();
+            if script.is_empty() { Trans::None } else { script.remove(0) }
+        }
+        fn start(&mut self, _token : ResourceToken) { self.record("start"); }
+        fn exit(&mut self, _token : ResourceToken) { self.record("exit"); }
+        fn pause(&mut self, _token : ResourceToken) { self.record("pause"); }
+        fn resume(&mut self, _token : ResourceToken) { self.record("resume"); }
+    }
+
+    fn state(name : &'static str, log : &Log, script : Vec<Trans>) -> State {
+        State::new().with(Box::new(Lifecycle {
+            name : name,
+            log : log.clone(),
+            script : Arc::new(Mutex::new(script)),
+        }))
+    }
+
+    fn shadowed(name : &'static str, log : &Log, script : Vec<Trans>, shadow : &'static str) -> State {
+        state(name, log, script).with_shadow(Box::new(Lifecycle {
+            name : shadow,
+            log : log.clone(),
+            script : Arc::new(Mutex::new(vec!(Trans::Quit))),
+        }))
+    }
+
+    fn drain(log : &Log) -> Vec<String> {
+        log.lock().unwrap().drain(..).collect()
+    }
+
+    #[test]
+    fn test_push_pop() {
+        let log = Log::default();
+        let menu = state("menu", &log, vec!(Trans::None, Trans::Pop));
+        let game = state("game", &log, vec!(Trans::Push(menu), Trans::None, Trans::Pop));
+        let mut machine = StateMachine::new(game);
+        machine.run();
+        assert!(drain(&log) == vec!(
+            "game start", "game update", "game pause", "menu start",
+            "menu update", "menu update", "menu exit", "game resume",
+            "game update", "game update", "game exit",
+        ));
+    }
+
+    #[test]
+    fn test_swap() {
+        let log = Log::default();
+        let level = state("level", &log, vec!(Trans::Quit));
+        let menu = state("menu", &log, vec!(Trans::Swap(level)));
+        let game = state("game", &log, vec!(Trans::Push(menu)));
+        let mut machine = StateMachine::new(game);
+        machine.run();
+        assert!(drain(&log) == vec!(
+            "game start", "game update", "game pause", "menu start",
+            "menu update", "menu exit", "level start",
+            "level update", "level exit", "game exit",
+        ));
+    }
+
+    #[test]
+    fn test_replace() {
+        let log = Log::default();
+        let loading = state("loading", &log, vec!(Trans::Pop));
+        let world = state("world", &log, vec!(Trans::Quit));
+        let menu = state("menu", &log, vec!(Trans::Replace(vec!(world, loading))));
+        let boot = state("boot", &log, vec!(Trans::Push(menu)));
+        let mut machine = StateMachine::new(boot);
+        machine.run();
+        assert!(drain(&log) == vec!(
+            "boot start", "boot update", "boot pause", "menu start",
+            "menu update", "menu exit", "boot exit",
+            "world start", "world pause", "loading start",
+            "loading update", "loading exit", "world resume",
+            "world update", "world exit",
+        ));
+    }
+
+    #[test]
+    fn test_shadow_update() {
+        let log = Log::default();
+        let menu = shadowed("menu", &log, vec!(Trans::None, Trans::Pop), "menu shadow");
+        let world = shadowed("world", &log, vec!(Trans::Push(menu), Trans::Quit), "render");
+        let mut machine = StateMachine::new(world);
+        machine.run();
+
+        // shadow systems only run while their state is paused, their transitions are ignored
+        assert!(drain(&log) == vec!(
+            "world start", "render start", "world update",
+            "world pause", "menu start", "menu shadow start",
+            "render update", "menu update",
+            "render update", "menu update", "menu exit", "menu shadow exit", "world resume",
+            "world update", "world exit", "render exit",
+        ));
+    }
+
+    /// Logs the Time it reads on every update
+    struct Clock {
+        name : &'static str,
+        request : ResourceRequest,
+        log : Arc<Mutex<Vec<(&'static str, Time)>>>,
+    }
+    impl System for Clock {
+        fn resources(&self) -> Option<&ResourceRequest> {
+            Some(&self.request)
+        }
+
+        fn update(&mut self, token : ResourceToken) -> Trans {
+            let token = token.request(&self.request).unwrap();
+            let time = *token.unpack_resource::<Time>().unwrap();
+            self.log.lock().unwrap().push((self.name, time));
+            Trans::None
+        }
+    }
+
+    #[test]
+    fn test_timestep() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let clock = |name| {
+            let mut request = ResourceRequest::new();
+            request.read_resource::<Time>();
+            Box::new(Clock { name : name, request : request, log : log.clone() })
+        };
+        let state = State::new()
+            .with(clock("frame"))
+            .with_fixed(clock("fixed"));
+        let mut machine = StateMachine::new(state)
+            .with_fixed_timestep(Duration::from_millis(10))
+            .with_max_frame_time(Duration::from_millis(50));
+
+        let mut frames = Vec::new();
+        for millis in vec!(5, 20, 100) {
+            machine.advance(Duration::from_millis(millis));
+            let log : Vec<(&'static str, Time)> = log.lock().unwrap().drain(..).collect();
+            let fixed : Vec<u64> = log.iter().filter(|(name, _)| *name == "fixed").map(|(_, time)| time.fixed_steps()).collect();
+            let (_, frame) = *log.last().unwrap();
+            assert!(log.iter().all(|(_, time)| time.frame() == frame.frame()));
+            frames.push((fixed, frame.delta(), (frame.alpha() * 100.0).round() as u64));
+        }
+
+        // the last frame is clamped to 50 milliseconds
+        assert!(frames == vec!(
+            (vec!(), Duration::from_millis(5), 50),
+            (vec!(1, 2), Duration::from_millis(20), 50),
+            (vec!(3, 4, 5, 6, 7), Duration::from_millis(50), 50),
+        ));
+        let mut request = ResourceRequest::new();
+        request.read_resource::<Time>();
+        let token = machine.resources.get_token().request(&request).unwrap();
+        let time = *token.unpack_resource::<Time>().unwrap();
+        assert!(time.elapsed() == Duration::from_millis(75) && time.frame() == 3);
+    }
+
+    struct Mark;
+    impl Component for Mark {
+        type Storage = ComponentVector<Self>;
+    }
+
+    /// Runs the function with the loaned token on every update
+    struct Closure<F> {
+        request : ResourceRequest,
+        function : F,
+    }
+    impl<F : FnMut(&ResourceToken) -> Trans + Send + Sync> System for Closure<F> {
+        fn resources(&self) -> Option<&ResourceRequest> {
+            Some(&self.request)
+        }
+
+        fn update(&mut self, token : ResourceToken) -> Trans {
+            let token = token.request(&self.request).unwrap();
+            (self.function)(&token)
+        }
+    }
+
+    fn closure<F : FnMut(&ResourceToken) -> Trans + Send + Sync + 'static>(write : bool, function : F) -> Box<System> {
+        let mut request = ResourceRequest::new();
+        if write {
+            request.write::<Mark>();
+        } else {
+            request.read::<Mark>();
+        }
+        Box::new(Closure { request : request, function : function })
+    }
+
+    /// Logs how many Marks were removed since the system last ran
+    fn watcher(log : &Arc<Mutex<Vec<usize>>>) -> Box<System> {
+        let log = log.clone();
+        closure(false, move |token| {
+            log.lock().unwrap().push(token.removed::<Mark>().len());
+            Trans::None
+        })
+    }
+
+    /// Removes the Mark of the entity on its first update and
+    /// returns the transition on its second update
+    fn remover(entity : Entity, then : Trans) -> Box<System> {
+        let mut then = Some(then);
+        let mut removed = false;
+        closure(true, move |token| {
+            if !removed {
+                removed = true;
+                Storage::remove(&mut *token.unpack_mut::<Mark>().unwrap(), entity.id());
+                return Trans::None;
+            }
+            then.take().unwrap_or(Trans::None)
+        })
+    }
+
+    fn marked(machine : &StateMachine) -> Entity {
+        let mut request = ResourceRequest::new();
+        request.write::<Mark>();
+        let token = machine.resources.get_token().request(&request).unwrap();
+        token.create_entity().with(Mark).build().unwrap()
+    }
+
+    #[test]
+    fn test_paused_removals() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut machine = StateMachine::new(State::new());
+        let entity = marked(&machine);
+        let menu = State::new().with(remover(entity, Trans::Pop));
+        let mut push = Some(Trans::Push(menu));
+        let world = State::new()
+            .with(watcher(&log))
+            .with(closure(false, move |_| push.take().unwrap_or(Trans::None)));
+        machine.transition(Trans::Swap(world));
+        for _ in 0..5 {
+            machine.advance(Duration::from_millis(1));
+        }
+
+        // the world was paused while the menu removed the Mark
+        assert!(*log.lock().unwrap() == vec!(0, 1, 0));
+    }
+
+    #[test]
+    fn test_shadow_removals() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut machine = StateMachine::new(State::new());
+        let entity = marked(&machine);
+        let menu = State::new().with(remover(entity, Trans::Pop));
+        let mut push = Some(Trans::Push(menu));
+        let world = State::new()
+            .with(closure(false, move |_| push.take().unwrap_or(Trans::None)))
+            .with_shadow(watcher(&log));
+        machine.transition(Trans::Swap(world));
+        for _ in 0..4 {
+            machine.advance(Duration::from_millis(1));
+        }
+
+        // the shadow system sees the removal the menu made the frame before
+        assert!(*log.lock().unwrap() == vec!(0, 1));
+    }
+
+    #[test]
+    fn test_active_shadow_removals() {
+        let mut machine = StateMachine::new(State::new());
+        let entity = marked(&machine);
+        let churn = closure(true, move |token| {
+            let mut marks = token.unpack_mut::<Mark>().unwrap();
+            marks.insert(entity.id(), Mark);
+            Storage::remove(&mut *marks, entity.id());
+            Trans::None
+        });
+        let world = State::new()
+            .with(churn)
+            .with_shadow(closure(false, |_| Trans::None));
+        machine.transition(Trans::Swap(world));
+        for _ in 0..100 {
+            machine.advance(Duration::from_millis(1));
+        }
+
+        // the shadow system does not run while the world is active, so it
+        // does not keep the removals of the world from being forgotten
+        let mut request = ResourceRequest::new();
+        request.read::<Mark>();
+        let token = machine.resources.get_token().request(&request).unwrap();
+        assert!(token.unpack::<Mark>().unwrap().changes().removed_since(0).len() == 0);
+    }
+
+    #[test]
+    fn test_fixed_maintenance() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut machine = StateMachine::new(State::new())
+            .with_fixed_timestep(Duration::from_millis(10));
+        let entity = marked(&machine);
+        machine.resources.register_channel(EventChannel::<u32>::with_lifetime(1));
+
+        let mut request = ResourceRequest::new();
+        request.write_events::<u32>();
+        let mut written = false;
+        let writer = Closure { request : request, function : move |token : &ResourceToken| {
+            if !written {
+                written = true;
+                token.events_mut::<u32>().unwrap().single_write(1);
+            }
+            Trans::None
+        }};
+        let world = State::new()
+            .with_fixed(remover(entity, Trans::None))
+            .with_fixed(Box::new(writer))
+            .with(watcher(&log));
+        machine.transition(Trans::Swap(world));
+
+        let mut request = ResourceRequest::new();
+        request.read_events::<u32>();
+        let _reader = machine.resources.get_token().request(&request).unwrap().events::<u32>().unwrap().register_reader();
+        let mut kept = Vec::new();
+        for _ in 0..3 {
+            // every frame runs two fixed steps
+            machine.advance(Duration::from_millis(25));
+            kept.push(machine.resources.get_token().request(&request).unwrap().events::<u32>().unwrap().len());
+        }
+
+        // the frame system sees the removal made in a fixed step, and the
+        // event is kept for one frame after the frame it was written in
+        assert!(*log.lock().unwrap() == vec!(1, 0, 0));
+        assert!(kept == vec!(1, 0, 0));
+    }
+
+    #[test]
+    fn test_fixed_quit() {
+        let mut machine = StateMachine::new(State::new())
+            .with_fixed_timestep(Duration::from_millis(10));
+        let entity = marked(&machine);
+        machine.transition(Trans::Swap(State::new().with_fixed(remover(entity, Trans::Quit))));
+
+        // the second of three fixed steps quits, the frame is still maintained
+        match machine.advance(Duration::from_millis(35)) {
+            UpdateStatus::Exit => (),
+            UpdateStatus::Continue => panic!("the fixed step quit the state machine"),
+        }
+        let mut request = ResourceRequest::new();
+        request.read::<Mark>();
+        let token = machine.resources.get_token().request(&request).unwrap();
+        assert!(!token.has::<Mark>(entity) && token.removed::<Mark>().is_empty());
+    }
+}
@@ -0,0 +1,811 @@
+use std::collections::HashMap;
+use std::any::Any;
+use std::mem::{replace, forget, size_of, needs_drop};
+use std::marker::PhantomData;
+use std::ptr::{self, NonNull};
+use bit_field::BitField;
+use resources::{Component, ComponentCollection, ComponentWrapper};
+use entity::{Entity, index_of};
+
+/*************************************************/
+/* A backend that stores a single Component type */
+/*************************************************/
+pub trait Storage<C : Component> : ComponentCollection + Sized + 'static {
+    /// Constructs an empty storage
+    fn new() -> Self;
+
+    /// Stores the component under the entity, returning the component
+    /// that was previously stored under the entity
+    fn insert(&mut self, entity_id : u64, component : C) -> Option<C>;
+
+    /// Removes the component stored under the entity
+    fn remove(&mut self, entity_id : u64) -> Option<C>;
+
+    /// Returns the component belonging to the entity
+    fn get(&self, entity_id : u64) -> Option<&C>;
+
+    /// Returns the component belonging to the entity mutably,
+    /// the component is marked as changed
+    fn get_mut(&mut self, entity_id : u64) -> Option<&mut C>;
+
+    /// Returns the tick the entity's component was added at
+    fn added(&self, entity_id : u64) -> Option<u64>;
+
+    /// Returns the tick the entity's component was last added or mutably accessed at
+    fn changed(&self, entity_id : u64) -> Option<u64>;
+
+    /// Returns true if the entity has a component that is turned on
+    fn is_enabled(&self, entity_id : u64) -> bool;
+
+    /// Turns the entity's component on or off, returns false if the
+    /// entity does not have a component in the storage
+    fn set_enabled(&mut self, entity_id : u64, on : bool) -> bool;
+
+    /// Returns the ids of every entity in the storage
+    fn entity_ids(&self) -> Vec<u64>;
+
+    /// Returns a pointer to every enabled component. The pointers are resolved in
+    /// a single pass so mutable references can be made from many of them at once
+    fn pointers(&mut self) -> Vec<(u64, ComponentPtr<C>)>;
+
+    /// Returns the number of components in the storage
+    fn len(&self) -> usize;
+
+    /// Reserves room for at least the number of additional components,
+    /// storages that can't make use of it ignore the hint
+    fn reserve(&mut self, _additional : usize) {}
+
+    /// Returns true if the entity has a component in the storage
+    fn contains(&self, entity_id : u64) -> bool {
+        self.added(entity_id).is_some()
+    }
+
+    /// Returns true if the entity's component was added after the tick
+    fn is_added(&self, entity_id : u64, since : u64) -> bool {
+        self.added(entity_id).map_or(false, |added| added > since)
+    }
+
+    /// Returns true if the entity's component was added or mutably accessed after the tick
+    fn is_changed(&self, entity_id : u64, since : u64) -> bool {
+        self.changed(entity_id).map_or(false, |changed| changed > since)
+    }
+
+    /// Turns the entity's component back on, returns false if the entity
+    /// does not have a component in the storage
+    fn enable(&mut self, entity_id : u64) -> bool {
+        self.set_enabled(entity_id, true)
+    }
+
+    /// Turns the entity's component off so iterators and joins skip it, returns
+    /// false if the entity does not have a component in the storage
+    fn disable(&mut self, entity_id : u64) -> bool {
+        self.set_enabled(entity_id, false)
+    }
+}
+
+/// Implements the accessors of the Storage trait for a storage that keeps every
+/// component in a ComponentWrapper, the storage provides `wrapper`, `wrapper_mut`,
+/// `wrappers` and `wrappers_mut`
+macro_rules! impl_wrapper_access {
+    ($component:ident) => {
+        fn get(&self, entity_id : u64) -> Option<&$component> {
+            self.wrapper(entity_id).map(|wrapper| &wrapper.component)
+        }
+
+        fn get_mut(&mut self, entity_id : u64) -> Option<&mut $component> {
+            let tick = self.changes().tick();
+            self.wrapper_mut(entity_id).map(|wrapper| {
+                wrapper.mark_changed(tick);
+                &mut wrapper.component
+            })
+        }
+
+        fn added(&self, entity_id : u64) -> Option<u64> {
+            self.wrapper(entity_id).map(|wrapper| wrapper.added())
+        }
+
+        fn changed(&self, entity_id : u64) -> Option<u64> {
+            self.wrapper(entity_id).map(|wrapper| wrapper.changed())
+        }
+
+        fn is_enabled(&self, entity_id : u64) -> bool {
+            self.wrapper(entity_id).map_or(false, |wrapper| wrapper.is_on())
+        }
+
+        fn set_enabled(&mut self, entity_id : u64, on : bool) -> bool {
+            self.wrapper_mut(entity_id).map(|wrapper| wrapper.set_on(on)).is_some()
+        }
+
+        fn entity_ids(&self) -> Vec<u64> {
+            self.wrappers().map(|wrapper| wrapper.get_entity()).collect()
+        }
+
+        fn pointers(&mut self) -> Vec<(u64, ComponentPtr<$component>)> {
+            self.wrappers_mut()
+                .filter(|wrapper| wrapper.is_on())
+                .map(|wrapper| (wrapper.get_entity(), wrapper.pointer()))
+                .collect()
+        }
+    };
+}
+
+/*************************************************/
+/* Points at a Component and its changed tick    */
+/*************************************************/
+/// Returned by `Storage::pointers`, the pointers stay valid until the storage is used again
+pub struct ComponentPtr<C> {
+    component : *mut C,
+    changed : *mut u64,
+}
+
+// A ComponentPtr only reaches a single component, sending or sharing it is as
+// safe as sending a reference to the component which get_mut's caller vouches for
+unsafe impl<C : Send + Sync> Send for ComponentPtr<C> {}
+unsafe impl<C : Send + Sync> Sync for ComponentPtr<C> {}
+
+impl<C> ComponentPtr<C> {
+    pub fn new(component : *mut C, changed : *mut u64) -> ComponentPtr<C> {
+        ComponentPtr {
+            component : component,
+            changed : changed,
+        }
+    }
+
+    /// Marks the component as changed at the tick and returns it. The storage must
+    /// not be used and no other reference to the component may be alive while it is held
+    pub(crate) unsafe fn get_mut<'a>(&self, tick : u64) -> &'a mut C {
+        *self.changed = tick;
+        &mut *self.component
+    }
+}
+
+/*************************************************/
+/* Records the tick Components are changed at    */
+/* and the entities whose Component was removed  */
+/*************************************************/
+pub struct Changes {
+    tick : u64,
+    removed : Vec<(u64, u64)>,
+}
+
+impl Changes {
+    pub fn new() -> Changes {
+        Changes {
+            tick : 0,
+            removed : Vec::new(),
+        }
+    }
+
+    /// Returns the tick that changes are currently stamped with
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    /// Sets the tick that following changes are stamped with
+    pub(crate) fn set_tick(&mut self, tick : u64) {
+        self.tick = tick;
+    }
+
+    /// Records that the entity's component was removed
+    pub(crate) fn record_removed(&mut self, entity_id : u64) {
+        self.removed.push((entity_id, self.tick));
+    }
+
+    /// Returns the entities whose component was removed after the tick
+    pub fn removed_since(&self, since : u64) -> Vec<Entity> {
+        self.removed.iter()
+            .filter(|(_, tick)| *tick > since)
+            .map(|(entity_id, _)| Entity::new_with_id(*entity_id))
+            .collect()
+    }
+
+    /// Forgets the removals recorded at or before the tick
+    pub(crate) fn clear_removed(&mut self, until : u64) {
+        self.removed.retain(|(_, tick)| *tick > until);
+    }
+}
+
+/*************************************************/
+/* Stores a slot for every entity index, best    */
+/* for Components that almost every entity has   */
+/*************************************************/
+pub struct DenseVecStorage<C : Component> {
+    slots : Vec<Option<ComponentWrapper<C>>>,
+    len : usize,
+    changes : Changes,
+}
+
+impl<C : Component> ComponentCollection for DenseVecStorage<C> {
+    fn remove_entity(&mut self, entity_id : u64) {
+        Storage::remove(self, entity_id);
+    }
+
+    fn as_any(&self) -> &Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut Any {
+        self
+    }
+
+    fn changes(&self) -> &Changes {
+        &self.changes
+    }
+
+    fn changes_mut(&mut self) -> &mut Changes {
+        &mut self.changes
+    }
+}
+
+impl<C : Component> DenseVecStorage<C> {
+    /// Returns the wrapper stored under the entity
+    fn wrapper(&self, entity_id : u64) -> Option<&ComponentWrapper<C>> {
+        match self.slots.get(index_of(entity_id) as usize) {
+            Some(&Some(ref wrapper)) if wrapper.get_entity() == entity_id => Some(wrapper),
+            _ => None,
+        }
+    }
+
+    /// Returns the wrapper stored under the entity mutably
+    fn wrapper_mut(&mut self, entity_id : u64) -> Option<&mut ComponentWrapper<C>> {
+        match self.slots.get_mut(index_of(entity_id) as usize) {
+            Some(&mut Some(ref mut wrapper)) if wrapper.get_entity() == entity_id => Some(wrapper),
+            _ => None,
+        }
+    }
+
+    /// Iterates over the wrappers in entity index order
+    fn wrappers<'a>(&'a self) -> Box<Iterator<Item = &'a ComponentWrapper<C>> + 'a> {
+        Box::new(self.slots.iter().filter_map(|slot| slot.as_ref()))
+    }
+
+    /// Iterates mutably over the wrappers in entity index order
+    fn wrappers_mut<'a>(&'a mut self) -> Box<Iterator<Item = &'a mut ComponentWrapper<C>> + 'a> {
+        Box::new(self.slots.iter_mut().filter_map(|slot| slot.as_mut()))
+    }
+}
+
+impl<C : Component> Storage<C> for DenseVecStorage<C> {
+    fn new() -> DenseVecStorage<C> {
+        DenseVecStorage {
+            slots : Vec::new(),
+            len : 0,
+            changes : Changes::new(),
+        }
+    }
+
+    fn insert(&mut self, entity_id : u64, component : C) -> Option<C> {
+        let tick = self.changes.tick();
+        if let Some(wrapper) = self.wrapper_mut(entity_id) {
+            wrapper.mark_changed(tick);
+            return Some(replace(&mut wrapper.component, component));
+        }
+        let slot = index_of(entity_id) as usize;
+        if self.slots.len() <= slot {
+            let missing = slot + 1 - self.slots.len();
+            self.slots.extend((0..missing).map(|_| None));
+        }
+        // A wrapper left behind by an older generation is replaced
+        if self.slots[slot].is_none() {
+            self.len += 1;
+        }
+        self.slots[slot] = Some(ComponentWrapper::new(component, entity_id, 0, true, tick));
+        None
+    }
+
+    fn remove(&mut self, entity_id : u64) -> Option<C> {
+        if !self.contains(entity_id) {
+            return None;
+        }
+        self.len -= 1;
+        self.changes.record_removed(entity_id);
+        self.slots[index_of(entity_id) as usize].take().map(|wrapper| wrapper.component)
+    }
+
+    impl_wrapper_access!(C);
+
+    fn len(&self) -> usize {
+        self.len
+    }
+}
+
+/*************************************************/
+/* Packs the Components tightly with a sparse    */
+/* array mapping entity indices to positions     */
+/*************************************************/
+pub struct SparseSetStorage<C : Component> {
+    dense : Vec<ComponentWrapper<C>>,
+    sparse : Vec<Option<usize>>,
+    changes : Changes,
+}
+
+impl<C : Component> ComponentCollection for SparseSetStorage<C> {
+    fn remove_entity(&mut self, entity_id : u64) {
+        Storage::remove(self, entity_id);
+    }
+
+    fn as_any(&self) -> &Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut Any {
+        self
+    }
+
+    fn changes(&self) -> &Changes {
+        &self.changes
+    }
+
+    fn changes_mut(&mut self) -> &mut Changes {
+        &mut self.changes
+    }
+}
+
+impl<C : Component> SparseSetStorage<C> {
+    /// Returns the position of the entity in the dense array
+    fn position(&self, entity_id : u64) -> Option<usize> {
+        match self.sparse.get(index_of(entity_id) as usize) {
+            Some(&Some(position)) if self.dense[position].get_entity() == entity_id => Some(position),
+            _ => None,
+        }
+    }
+
+    /// Returns the wrapper stored under the entity
+    fn wrapper(&self, entity_id : u64) -> Option<&ComponentWrapper<C>> {
+        match self.position(entity_id) {
+            Some(position) => Some(&self.dense[position]),
+            None => None,
+        }
+    }
+
+    /// Returns the wrapper stored under the entity mutably
+    fn wrapper_mut(&mut self, entity_id : u64) -> Option<&mut ComponentWrapper<C>> {
+        match self.position(entity_id) {
+            Some(position) => Some(&mut self.dense[position]),
+            None => None,
+        }
+    }
+
+    /// Iterates over the wrappers in the order they are packed
+    fn wrappers<'a>(&'a self) -> Box<Iterator<Item = &'a ComponentWrapper<C>> + 'a> {
+        Box::new(self.dense.iter())
+    }
+
+    /// Iterates mutably over the wrappers in the order they are packed
+    fn wrappers_mut<'a>(&'a mut self) -> Box<Iterator<Item = &'a mut ComponentWrapper<C>> + 'a> {
+        Box::new(self.dense.iter_mut())
+    }
+}
+
+impl<C : Component> Storage<C> for SparseSetStorage<C> {
+    fn new() -> SparseSetStorage<C> {
+        SparseSetStorage {
+            dense : Vec::new(),
+            sparse : Vec::new(),
+            changes : Changes::new(),
+        }
+    }
+
+    fn insert(&mut self, entity_id : u64, component : C) -> Option<C> {
+        let tick = self.changes.tick();
+        if let Some(position) = self.position(entity_id) {
+            self.dense[position].mark_changed(tick);
+            return Some(replace(&mut self.dense[position].component, component));
+        }
+        let slot = index_of(entity_id) as usize;
+        if self.sparse.len() <= slot {
+            self.sparse.resize(slot + 1, None);
+        }
+        // A wrapper left behind by an older generation is replaced
+        if let Some(position) = self.sparse[slot] {
+            self.dense[position] = ComponentWrapper::new(component, entity_id, 0, true, tick);
+            return None;
+        }
+        self.sparse[slot] = Some(self.dense.len());
+        self.dense.push(ComponentWrapper::new(component, entity_id, 0, true, tick));
+        None
+    }
+
+    fn remove(&mut self, entity_id : u64) -> Option<C> {
+        let position = match self.position(entity_id) {
+            Some(position) => position,
+            None => return None,
+        };
+        self.sparse[index_of(entity_id) as usize] = None;
+        self.changes.record_removed(entity_id);
+
+        // The last wrapper is moved into the removed position
+        let wrapper = self.dense.swap_remove(position);
+        if position < self.dense.len() {
+            let moved = self.dense[position].get_entity();
+            self.sparse[index_of(moved) as usize] = Some(position);
+        }
+        Some(wrapper.component)
+    }
+
+    impl_wrapper_access!(C);
+
+    fn len(&self) -> usize {
+        self.dense.len()
+    }
+
+    fn reserve(&mut self, additional : usize) {
+        self.dense.reserve(additional);
+    }
+}
+
+/*************************************************/
+/* Stores the Components in a HashMap, best for  */
+/* Components that only a few entities have      */
+/*************************************************/
+pub struct HashMapStorage<C : Component> {
+    components : HashMap<u64, ComponentWrapper<C>>,
+    changes : Changes,
+}
+
+impl<C : Component> ComponentCollection for HashMapStorage<C> {
+    fn remove_entity(&mut self, entity_id : u64) {
+        Storage::remove(self, entity_id);
+    }
+
+    fn as_any(&self) -> &Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut Any {
+        self
+    }
+
+    fn changes(&self) -> &Changes {
+        &self.changes
+    }
+
+    fn changes_mut(&mut self) -> &mut Changes {
+        &mut self.changes
+    }
+}
+
+impl<C : Component> HashMapStorage<C> {
+    /// Returns the wrapper stored under the entity
+    fn wrapper(&self, entity_id : u64) -> Option<&ComponentWrapper<C>> {
+        self.components.get(&entity_id)
+    }
+
+    /// Returns the wrapper stored under the entity mutably
+    fn wrapper_mut(&mut self, entity_id : u64) -> Option<&mut ComponentWrapper<C>> {
+        self.components.get_mut(&entity_id)
+    }
+
+    /// Iterates over the wrappers in no particular order
+    fn wrappers<'a>(&'a self) -> Box<Iterator<Item = &'a ComponentWrapper<C>> + 'a> {
+        Box::new(self.components.values())
+    }
+
+    /// Iterates mutably over the wrappers in no particular order
+    fn wrappers_mut<'a>(&'a mut self) -> Box<Iterator<Item = &'a mut ComponentWrapper<C>> + 'a> {
+        Box::new(self.components.values_mut())
+    }
+}
+
+impl<C : Component> Storage<C> for HashMapStorage<C> {
+    fn new() -> HashMapStorage<C> {
+        HashMapStorage {
+            components : HashMap::new(),
+            changes : Changes::new(),
+        }
+    }
+
+    fn insert(&mut self, entity_id : u64, component : C) -> Option<C> {
+        let tick = self.changes.tick();
+        if let Some(wrapper) = self.components.get_mut(&entity_id) {
+            wrapper.mark_changed(tick);
+            return Some(replace(&mut wrapper.component, component));
+        }
+        self.components.insert(entity_id, ComponentWrapper::new(component, entity_id, 0, true, tick));
+        None
+    }
+
+    fn remove(&mut self, entity_id : u64) -> Option<C> {
+        let wrapper = self.components.remove(&entity_id)?;
+        self.changes.record_removed(entity_id);
+        Some(wrapper.component)
+    }
+
+    impl_wrapper_access!(C);
+
+    fn len(&self) -> usize {
+        self.components.len()
+    }
+
+    fn reserve(&mut self, additional : usize) {
+        self.components.reserve(additional);
+    }
+}
+
+/*************************************************/
+/* Stores zero sized marker Components as a set  */
+/* of entity ids along with their change ticks   */
+/*************************************************/
+pub struct NullStorage<C : Component> {
+    marks : Vec<Mark>,
+    sparse : Vec<Option<usize>>,
+    changes : Changes,
+    marker : PhantomData<C>,
+}
+
+/// Everything a NullStorage keeps for a marked entity, the
+/// top bit of the added tick is set while the marker is off
+struct Mark {
+    entity : u64,
+    added : u64,
+    changed : u64,
+}
+
+impl Mark {
+    fn is_on(&self) -> bool {
+        !self.added.get_bit(OFF_BIT)
+    }
+
+    fn added(&self) -> u64 {
+        let mut added = self.added;
+        *added.set_bit(OFF_BIT, false)
+    }
+}
+
+const OFF_BIT : usize = 63;
+
+impl<C : Component> ComponentCollection for NullStorage<C> {
+    fn remove_entity(&mut self, entity_id : u64) {
+        Storage::remove(self, entity_id);
+    }
+
+    fn as_any(&self) -> &Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut Any {
+        self
+    }
+
+    fn changes(&self) -> &Changes {
+        &self.changes
+    }
+
+    fn changes_mut(&mut self) -> &mut Changes {
+        &mut self.changes
+    }
+}
+
+impl<C : Component> NullStorage<C> {
+    /// Using a NullStorage for a Component that is not zero sized fails to compile
+    const ZERO_SIZED : () = assert!(size_of::<C>() == 0, "NullStorage can only store zero sized Components");
+
+    /// Returns a marker, no marker is ever stored since they take no space
+    fn marker() -> &'static mut C {
+        let () = Self::ZERO_SIZED;
+        // A dangling pointer is a valid place for a zero sized value
+        unsafe { &mut *NonNull::dangling().as_ptr() }
+    }
+
+    /// Returns the position of the entity's mark
+    fn position(&self, entity_id : u64) -> Option<usize> {
+        match self.sparse.get(index_of(entity_id) as usize) {
+            Some(&Some(position)) if self.marks[position].entity == entity_id => Some(position),
+            _ => None,
+        }
+    }
+
+    /// Returns the mark of the entity
+    fn mark(&self, entity_id : u64) -> Option<&Mark> {
+        self.position(entity_id).map(|position| &self.marks[position])
+    }
+}
+
+impl<C : Component> Storage<C> for NullStorage<C> {
+    fn new() -> NullStorage<C> {
+        let () = Self::ZERO_SIZED;
+        NullStorage {
+            marks : Vec::new(),
+            sparse : Vec::new(),
+            changes : Changes::new(),
+            marker : PhantomData,
+        }
+    }
+
+    fn insert(&mut self, entity_id : u64, component : C) -> Option<C> {
+        let tick = self.changes.tick();
+        if let Some(position) = self.position(entity_id) {
+            self.marks[position].changed = tick;
+            return Some(component);
+        }
+        // The marker is stored by remembering the entity, it is handed back by remove
+        forget(component);
+        let mark = Mark { entity : entity_id, added : tick, changed : tick };
+        let slot = index_of(entity_id) as usize;
+        if self.sparse.len() <= slot {
+            self.sparse.resize(slot + 1, None);
+        }
+        // A mark left behind by an older generation is replaced
+        if let Some(position) = self.sparse[slot] {
+            self.marks[position] = mark;
+            return None;
+        }
+        self.sparse[slot] = Some(self.marks.len());
+        self.marks.push(mark);
+        None
+    }
+
+    fn remove(&mut self, entity_id : u64) -> Option<C> {
+        let position = self.position(entity_id)?;
+        self.sparse[index_of(entity_id) as usize] = None;
+        self.changes.record_removed(entity_id);
+
+        // The last mark is moved into the removed position
+        self.marks.swap_remove(position);
+        if position < self.marks.len() {
+            let moved = self.marks[position].entity;
+            self.sparse[index_of(moved) as usize] = Some(position);
+        }
+        Some(unsafe { ptr::read(Self::marker()) })
+    }
+
+    fn get(&self, entity_id : u64) -> Option<&C> {
+        self.position(entity_id).map(|_| &*Self::marker())
+    }
+
+    fn get_mut(&mut self, entity_id : u64) -> Option<&mut C> {
+        let tick = self.changes.tick();
+        let position = self.position(entity_id)?;
+        self.marks[position].changed = tick;
+        Some(Self::marker())
+    }
+
+    fn added(&self, entity_id : u64) -> Option<u64> {
+        self.mark(entity_id).map(|mark| mark.added())
+    }
+
+    fn changed(&self, entity_id : u64) -> Option<u64> {
+        self.mark(entity_id).map(|mark| mark.changed)
+    }
+
+    fn is_enabled(&self, entity_id : u64) -> bool {
+        self.mark(entity_id).map_or(false, |mark| mark.is_on())
+    }
+
+    fn set_enabled(&mut self, entity_id : u64, on : bool) -> bool {
+        match self.position(entity_id) {
+            Some(position) => {
+                self.marks[position].added.set_bit(OFF_BIT, !on);
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn entity_ids(&self) -> Vec<u64> {
+        self.marks.iter().map(|mark| mark.entity).collect()
+    }
+
+    fn pointers(&mut self) -> Vec<(u64, ComponentPtr<C>)> {
+        self.marks.iter_mut()
+            .filter(|mark| mark.is_on())
+            .map(|mark| (mark.entity, ComponentPtr::new(NonNull::dangling().as_ptr(), &mut mark.changed)))
+            .collect()
+    }
+
+    fn len(&self) -> usize {
+        self.marks.len()
+    }
+
+    fn reserve(&mut self, additional : usize) {
+        self.marks.reserve(additional);
+    }
+}
+
+impl<C : Component> Drop for NullStorage<C> {
+    /// Drops the markers that are still stored
+    fn drop(&mut self) {
+        if needs_drop::<C>() {
+            for _ in self.marks.iter() {
+                drop(unsafe { ptr::read(Self::marker()) });
+            }
+        }
+    }
+}
+
+/*************************************************/
+/* Unit Tests                                    */
+/*************************************************/
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use resources::ComponentVector;
+    use entity::Entity;
+
+    struct Comp(u64);
+    impl Component for Comp {
+        type Storage = ComponentVector<Self>;
+    }
+
+    struct Marker;
+    impl Component for Marker {
+        type Storage = NullStorage<Self>;
+    }
+
+    /// Runs the same inserts and removals against any storage
+    fn exercise<S : Storage<Comp>>() {
+        let mut storage = S::new();
+        storage.changes_mut().set_tick(1);
+        for id in 0..20 {
+            assert!(storage.insert(id, Comp(id)).is_none());
+        }
+        assert!(storage.len() == 20);
+        storage.changes_mut().set_tick(2);
+        assert!(storage.insert(3, Comp(30)).unwrap().0 == 3);
+        assert!(storage.get(3).unwrap().0 == 30);
+        storage.get_mut(3).unwrap().0 = 3;
+        storage.get_mut(4).unwrap().0 = 4;
+        assert!(storage.is_changed(3, 1) && storage.is_changed(4, 1) && !storage.is_changed(5, 1));
+        assert!(storage.is_added(3, 0) && !storage.is_added(3, 1));
+
+        for id in (0..20).filter(|id| id % 3 == 0) {
+            assert!(storage.remove(id).unwrap().0 == id);
+        }
+        assert!(storage.remove(0).is_none());
+        assert!(storage.remove(100).is_none());
+        assert!(storage.len() == 13);
+        assert!(storage.changes().removed_since(1).len() == 7);
+
+        for id in 0..20 {
+            assert!(storage.contains(id) == (id % 3 != 0));
+            if let Some(comp) = storage.get(id) {
+                assert!(comp.0 == id);
+            }
+        }
+        let mut ids = storage.entity_ids();
+        ids.sort();
+        let expected : Vec<u64> = (0..20).filter(|id| id % 3 != 0).collect();
+        assert!(ids == expected);
+
+        // a stale generation of a stored index is not found
+        let stale = Entity::new(1, 1).id();
+        assert!(!storage.contains(stale));
+        assert!(storage.get(stale).is_none());
+        assert!(storage.remove(stale).is_none());
+        assert!(storage.get(1).unwrap().0 == 1);
+    }
+
+    #[test]
+    fn test_storages() {
+        exercise::<ComponentVector<Comp>>();
+        exercise::<DenseVecStorage<Comp>>();
+        exercise::<SparseSetStorage<Comp>>();
+        exercise::<HashMapStorage<Comp>>();
+    }
+
+    #[test]
+    fn test_null_storage() {
+        let mut storage : NullStorage<Marker> = Storage::new();
+        storage.changes_mut().set_tick(1);
+        assert!(storage.insert(4, Marker).is_none());
+        assert!(storage.insert(9, Marker).is_none());
+        storage.changes_mut().set_tick(2);
+        assert!(storage.insert(4, Marker).is_some());
+        assert!(storage.contains(4) && storage.get(4).is_some());
+        assert!(storage.is_added(4, 0) && !storage.is_added(4, 1) && storage.is_changed(4, 1));
+        assert!(!storage.is_changed(9, 1));
+
+        assert!(storage.disable(9) && !storage.is_enabled(9));
+        assert!(storage.added(9) == Some(1));
+        assert!(storage.enable(9) && storage.is_enabled(9));
+
+        assert!(storage.remove(4).is_some());
+        assert!(storage.remove(4).is_none());
+        assert!(!storage.contains(4));
+        assert!(storage.entity_ids() == vec!(9));
+        assert!(storage.changes().removed_since(1).len() == 1);
+
+        // a stale generation of a stored index is not found
+        assert!(!storage.contains(Entity::new(9, 1).id()));
+    }
+}
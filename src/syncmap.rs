@@ -4,7 +4,9 @@ use std::hash::Hash;
 use std::{fmt,error};
 use std::error::Error;
 use std::cell::{RefCell,RefMut};
-use std::fmt::{Display};
+use std::fmt::{Display, Debug};
+use std::time::{Duration, Instant};
+use std::thread::{self, ThreadId};
 
 /************************************************************/
 /* States whether a request is for Read or Write Permisions */
@@ -20,6 +22,7 @@ enum RequestType {
 /*************************************************************/
 pub struct Request<K : Eq + Hash> {
     resources : Vec<(K,RequestType)>,
+    label : Option<String>,
 }
 
 impl<K : Eq + Hash> Request<K>{
@@ -27,9 +30,17 @@ impl<K : Eq + Hash> Request<K>{
     pub fn new() -> Request<K> {
         Request {
             resources : Vec::new(),
+            label : None,
         }
     }
 
+    /// Names the owner of the request, such as the name of a system,
+    /// the label shows up in the diagnostics of the SyncMap
+    pub fn label(&mut self, label : &str) -> &mut Self {
+        self.label = Some(label.to_string());
+        self
+    }
+
     /// Adds a key to the request, asking for read permisions
     pub fn read(&mut self, r : K) -> &mut Self {
         self.resources.push((r,RequestType::Read));
@@ -41,6 +52,19 @@ impl<K : Eq + Hash> Request<K>{
         self.resources.push((r,RequestType::Write));
         self
     }
+
+    /// Returns true if both requests could not be loaned out at the same
+    /// time, which is when either of them writes to a key the other uses
+    pub fn conflicts(&self, other : &Request<K>) -> bool {
+        self.resources.iter().any(|(key, access)| {
+            other.resources.iter().any(|(other_key, other_access)| {
+                key == other_key && match (access, other_access) {
+                    (RequestType::Read, RequestType::Read) => false,
+                    _ => true,
+                }
+            })
+        })
+    }
 }
 
 /************************************************************/
@@ -54,6 +78,7 @@ trait Loaner<K : Eq + Hash,V> {
 /* A Loan stores loaned resources for reads and writes      */
 /************************************************************/
 pub struct Loan<'a, K : 'a + Eq + Hash, V : 'a> {
+    id: u64,
     reads: HashMap<K, &'a V>,
     writes: HashMap<K, RefCell<&'a mut V>>,
     owner: &'a Loaner<K, V>,
@@ -152,13 +177,186 @@ impl<V> RwInfo<V> {
     }
 }
 
+/************************************************************/
+/* Describes who holds or waits on a set of keys            */
+/************************************************************/
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LoanInfo<K> {
+    pub label : Option<String>,
+    pub thread : ThreadId,
+    pub reads : Vec<K>,
+    pub writes : Vec<K>,
+}
+
+impl<K : Eq + Hash + Clone> LoanInfo<K> {
+    /// Describes a request made on the current thread
+    fn of(request : &Request<K>) -> LoanInfo<K> {
+        let mut info = LoanInfo {
+            label : request.label.clone(),
+            thread : thread::current().id(),
+            reads : Vec::new(),
+            writes : Vec::new(),
+        };
+        for (key, access) in request.resources.iter() {
+            match access {
+                RequestType::Read => info.reads.push(key.clone()),
+                RequestType::Write => info.writes.push(key.clone()),
+            }
+        }
+        info
+    }
+
+    /// Returns true if this loan keeps the waiting request from being fufilled
+    fn blocks(&self, waiting : &LoanInfo<K>) -> bool {
+        waiting.writes.iter().any(|key| self.reads.contains(key) || self.writes.contains(key))
+            || waiting.reads.iter().any(|key| self.writes.contains(key))
+    }
+
+    /// Converts the keys, useful for giving them readable names
+    pub fn map<T, F : Fn(&K) -> T>(&self, f : F) -> LoanInfo<T> {
+        LoanInfo {
+            label : self.label.clone(),
+            thread : self.thread,
+            reads : self.reads.iter().map(|key| f(key)).collect(),
+            writes : self.writes.iter().map(|key| f(key)).collect(),
+        }
+    }
+}
+
+impl<K : Debug> Display for LoanInfo<K> {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self.label {
+            Some(ref label) => write!(f, "{} on {:?}", label, self.thread)?,
+            None => write!(f, "unlabeled on {:?}", self.thread)?,
+        }
+        write!(f, " reads {:?} writes {:?}", self.reads, self.writes)
+    }
+}
+
+/************************************************************/
+/* A snapshot of the outstanding loans and waiting requests */
+/************************************************************/
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostics<K> {
+    pub loans : Vec<LoanInfo<K>>,
+    pub waiting : Vec<LoanInfo<K>>,
+}
+
+impl<K : Eq + Hash + Clone> Diagnostics<K> {
+    /// Converts the keys, useful for giving them readable names
+    pub fn map<T, F : Fn(&K) -> T>(&self, f : F) -> Diagnostics<T> {
+        Diagnostics {
+            loans : self.loans.iter().map(|loan| loan.map(&f)).collect(),
+            waiting : self.waiting.iter().map(|wait| wait.map(&f)).collect(),
+        }
+    }
+}
+
+impl<K : Debug> Display for Diagnostics<K> {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        for loan in self.loans.iter() {
+            writeln!(f, "loan held by {}", loan)?;
+        }
+        for wait in self.waiting.iter() {
+            writeln!(f, "request waiting from {}", wait)?;
+        }
+        Ok(())
+    }
+}
+
+/************************************************************/
+/* Keeps track of the loans and waits of a SyncMap          */
+/************************************************************/
+struct Tracker<K> {
+    next_id : u64,
+    loans : HashMap<u64, LoanInfo<K>>,
+    // waits that block forever are the only ones that can deadlock
+    waiting : HashMap<u64, (LoanInfo<K>, bool)>,
+    detect : bool,
+}
+
+impl<K : Eq + Hash + Clone> Tracker<K> {
+    fn new() -> Tracker<K> {
+        Tracker {
+            next_id : 0,
+            loans : HashMap::new(),
+            waiting : HashMap::new(),
+            detect : false,
+        }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    /// Follows the requests waiting forever through the threads holding
+    /// the loans that block them. A deadlock is found when the chain leads
+    /// back to the starting thread, every request on the chain and the
+    /// loans blocking them are returned
+    fn deadlock_from(&self, start : ThreadId) -> Option<Diagnostics<K>> {
+        let waits : HashMap<ThreadId, &LoanInfo<K>> = self.waiting.values()
+            .filter(|(_, forever)| *forever)
+            .map(|(info, _)| (info.thread, info))
+            .collect();
+        let mut path : Vec<ThreadId> = vec!(start);
+        let mut blockers : Vec<Vec<&LoanInfo<K>>> = Vec::new();
+        let mut visited : Vec<ThreadId> = Vec::new();
+        self.search(&waits, &mut path, &mut blockers, &mut visited)
+    }
+
+    fn search<'a>(&'a self, waits : &HashMap<ThreadId, &'a LoanInfo<K>>, path : &mut Vec<ThreadId>,
+                  blockers : &mut Vec<Vec<&'a LoanInfo<K>>>, visited : &mut Vec<ThreadId>) -> Option<Diagnostics<K>> {
+        let current = *path.last().unwrap();
+        let wait = match waits.get(&current) {
+            Some(wait) => *wait,
+            None => return None,
+        };
+        visited.push(current);
+        let blocking : Vec<&LoanInfo<K>> = self.loans.values().filter(|loan| loan.blocks(wait)).collect();
+        let mut next : Vec<ThreadId> = blocking.iter().map(|loan| loan.thread).collect();
+        next.dedup();
+        for thread in next {
+            let holding = blocking.iter().cloned().filter(|loan| loan.thread == thread).collect();
+            blockers.push(holding);
+            if thread == path[0] {
+                return Some(Diagnostics {
+                    loans : blockers.iter().flat_map(|loans| loans.iter().map(|loan| (*loan).clone())).collect(),
+                    waiting : path.iter().map(|thread| waits[thread].clone()).collect(),
+                });
+            }
+            if !visited.contains(&thread) {
+                path.push(thread);
+                if let Some(found) = self.search(waits, path, blockers, visited) {
+                    return Some(found);
+                }
+                path.pop();
+            }
+            blockers.pop();
+        }
+        None
+    }
+}
+
+/************************************************************/
+/* How long a request is willing to wait for its resources  */
+/************************************************************/
+enum Wait {
+    Forever,
+    Never,
+    Until(Instant),
+}
+
 /************************************************************/
 /* A map that can loan out it's resources with RWLock       */
 /************************************************************/
+/// Every value is boxed so that a new key can be inserted while loans
+/// are out without moving the values those loans point into
 pub struct SyncMap<K : Eq + Hash, V> {
-    map : RwInfo<HashMap<K, RwInfo<V>>>,
+    map : RwInfo<HashMap<K, Box<RwInfo<V>>>>,
     mutex : Mutex<()>,
     condvar : Condvar,
+    tracker : Mutex<Tracker<K>>,
 }
 
 impl<K : Eq + Hash + Clone, V> SyncMap<K, V> {
@@ -168,40 +366,83 @@ impl<K : Eq + Hash + Clone, V> SyncMap<K, V> {
             map : RwInfo::new(HashMap::new()),
             mutex : Mutex::new(()),
             condvar : Condvar::new(),
+            tracker : Mutex::new(Tracker::new()),
+        }
+    }
+
+    /// When enabled, a request that would wait on loans which can never be
+    /// returned, because their owners are themselves waiting on this thread,
+    /// returns a Deadlock error instead of blocking forever
+    pub fn set_deadlock_detection(&self, enabled : bool) {
+        self.tracker.lock().unwrap().detect = enabled;
+    }
+
+    /// Returns a snapshot of the outstanding loans and the requests waiting on them
+    pub fn diagnostics(&self) -> Diagnostics<K> {
+        let _guard = self.mutex.lock();
+        let tracker = self.tracker.lock().unwrap();
+        Diagnostics {
+            loans : tracker.loans.values().cloned().collect(),
+            waiting : tracker.waiting.values().map(|(info, _)| info.clone()).collect(),
+        }
+    }
+
+    /// Returns the requests and loans involved in a deadlock, if there is one
+    pub fn find_deadlock(&self) -> Option<Diagnostics<K>> {
+        let _guard = self.mutex.lock();
+        let tracker = self.tracker.lock().unwrap();
+        let threads : Vec<ThreadId> = tracker.waiting.values().map(|(info, _)| info.thread).collect();
+        threads.into_iter().filter_map(|thread| tracker.deadlock_from(thread)).next()
+    }
+
+    /// Records that the request is about to wait, if deadlock detection is
+    /// enabled and the wait can never end the deadlock is returned instead
+    fn start_wait(&self, request : &Request<K>, forever : bool) -> Result<u64,SyncMapError<K>> {
+        let mut tracker = self.tracker.lock()?;
+        let info = LoanInfo::of(request);
+        let thread = info.thread;
+        let id = tracker.next();
+        tracker.waiting.insert(id, (info, forever));
+        if forever && tracker.detect {
+            if let Some(deadlock) = tracker.deadlock_from(thread) {
+                tracker.waiting.remove(&id);
+                return Err(SyncMapError::Deadlock(deadlock));
+            }
         }
+        Ok(id)
+    }
+
+    /// Records that the request is no longer waiting
+    fn end_wait(&self, id : u64) -> Result<(),SyncMapError<K>> {
+        self.tracker.lock()?.waiting.remove(&id);
+        Ok(())
     }
 
     /// Unsafe function that returns a mutable refrence to self
     /// but requires that the mutex guard be passed in
-    fn map_as_mut(&self, _guard : &MutexGuard<()>) -> &mut RwInfo<HashMap<K, RwInfo<V>>> {
-        unsafe { (&self.map as *const RwInfo<HashMap<K, RwInfo<V>>> as *mut RwInfo<HashMap<K, RwInfo<V>>>).as_mut().unwrap() }
+    fn map_as_mut(&self, _guard : &MutexGuard<()>) -> &mut RwInfo<HashMap<K, Box<RwInfo<V>>>> {
+        unsafe { (&self.map as *const RwInfo<HashMap<K, Box<RwInfo<V>>>> as *mut RwInfo<HashMap<K, Box<RwInfo<V>>>>).as_mut().unwrap() }
     }
 
     /// Inserts a new value into the SyncMap only if the key has not been entered
-    /// before, does nothing if a key is already in the SyncMap
+    /// before, does nothing if a key is already in the SyncMap. Inserting does
+    /// not wait on the loans that are out, so it can be called while holding one
     pub fn insert(&self, key : K, value : V) -> Result<(),PoisionSyncMapError>{
-        let mut guard = self.mutex.lock()?;
-        loop {
-            // First check to see if there is already a value at that location
-            if self.map.can_read(){
-                let map = self.map_as_mut(&guard);
-                if let Some(_) = map.value.get(&key){
-                    drop(guard);
-                    self.condvar.notify_all();
-                    return Ok(());
-                }
-            }
-            // If the map doesnt already have a value initialize one
-            if self.map.can_write() {
-                let map = self.map_as_mut(&guard);
-                map.value.insert(key, RwInfo::new(value));
-                drop(guard);
-                self.condvar.notify_all();
-                return Ok(());
-            }
-            // Block if could not complete
-            guard = self.condvar.wait(guard)?;
+        let guard = self.mutex.lock()?;
+        let map = self.map_as_mut(&guard);
+        if !map.value.contains_key(&key) {
+            map.value.insert(key, Box::new(RwInfo::new(value)));
         }
+        drop(guard);
+        self.condvar.notify_all();
+        Ok(())
+    }
+
+    /// Returns the keys of every value currently in the SyncMap
+    pub fn keys(&self) -> Result<Vec<K>,PoisionSyncMapError> {
+        let guard = self.mutex.lock()?;
+        let map = self.map_as_mut(&guard);
+        Ok(map.value.keys().cloned().collect())
     }
 
     /// Given a request of keys with read and write permisions
@@ -211,7 +452,26 @@ impl<K : Eq + Hash + Clone, V> SyncMap<K, V> {
     /// an invalid key will cause this function to return with either an error
     /// or with a None. Once it can fufill the request, it will return a Loan
     /// on the request.
-    pub fn request(&self, request : &Request<K>) -> Result<Option<Loan<K,V>>,PoisionSyncMapError> {
+    /// If deadlock detection is enabled a Deadlock error is returned
+    /// instead of waiting on a request that can never be fufilled.
+    pub fn request(&self, request : &Request<K>) -> Result<Option<Loan<K,V>>,SyncMapError<K>> {
+        self.request_with(request, Wait::Forever)
+    }
+
+    /// Same as request, but returns a WouldBlock error instead of
+    /// blocking if the request can not be fufilled right away
+    pub fn try_request(&self, request : &Request<K>) -> Result<Option<Loan<K,V>>,SyncMapError<K>> {
+        self.request_with(request, Wait::Never)
+    }
+
+    /// Same as request, but returns a TimedOut error if the request
+    /// could not be fufilled before the timeout ran out
+    pub fn request_timeout(&self, request : &Request<K>, timeout : Duration) -> Result<Option<Loan<K,V>>,SyncMapError<K>> {
+        self.request_with(request, Wait::Until(Instant::now() + timeout))
+    }
+
+    /// Fufills the request, waiting for the resources as long as allowed
+    fn request_with(&self, request : &Request<K>, wait : Wait) -> Result<Option<Loan<K,V>>,SyncMapError<K>> {
         let mut guard = self.mutex.lock()?;
         loop {
             if self.map.can_read(){
@@ -252,16 +512,22 @@ impl<K : Eq + Hash + Clone, V> SyncMap<K, V> {
                             (RequestType::Write, Some(value)) if value.can_write() => {
                                 writes.insert((*key).clone(), value.write(&guard));
                             },
-                            _ => return Err(PoisionSyncMapError()),
+                            _ => return Err(SyncMapError::Poisoned),
                         };
                     }
 
                     // Signal there is a reader active on the SyncMap
                     map.read(&guard);
+                    // Record who holds the loan
+                    let mut tracker = self.tracker.lock()?;
+                    let id = tracker.next();
+                    tracker.loans.insert(id, LoanInfo::of(request));
+                    drop(tracker);
                     // Clean up and return the new Loan
                     drop(guard);
                     self.condvar.notify_all();
                     return Ok(Some(Loan {
+                        id: id,
                         reads: reads,
                         writes: writes,
                         owner: self,
@@ -269,9 +535,37 @@ impl<K : Eq + Hash + Clone, V> SyncMap<K, V> {
                 }
             }
             // Block if could not complete
-            guard = self.condvar.wait(guard)?;
+            guard = match wait {
+                Wait::Forever => {
+                    let waiting = self.start_wait(request, true)?;
+                    let guard = self.condvar.wait(guard)?;
+                    self.end_wait(waiting)?;
+                    guard
+                },
+                Wait::Never => return Err(SyncMapError::WouldBlock),
+                Wait::Until(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(SyncMapError::TimedOut);
+                    }
+                    let waiting = self.start_wait(request, false)?;
+                    let guard = match self.condvar.wait_timeout(guard, deadline - now) {
+                        Ok((guard, _)) => guard,
+                        Err(_) => return Err(SyncMapError::Poisoned),
+                    };
+                    self.end_wait(waiting)?;
+                    guard
+                }
+            };
         }
     }
+
+    /// Returns true if the SyncMap contains a value for the key
+    pub fn contains_key(&self, key : &K) -> Result<bool,PoisionSyncMapError> {
+        let guard = self.mutex.lock()?;
+        let map = self.map_as_mut(&guard);
+        Ok(map.value.contains_key(key))
+    }
 }
 
 impl<K : Eq + Hash + Clone,V> Loaner<K,V> for SyncMap<K,V> {
@@ -287,6 +581,7 @@ impl<K : Eq + Hash + Clone,V> Loaner<K,V> for SyncMap<K,V> {
             map.value.get(writer).unwrap().unwrite(&guard);
         }
         map.unread(&guard);
+        self.tracker.lock().unwrap().loans.remove(&loan.id);
         drop(guard);
         self.condvar.notify_all();
     }
@@ -316,6 +611,38 @@ impl<'a> From<PoisonError<MutexGuard<'a,()>>> for PoisionSyncMapError {
     }    
 }
 
+/************************************************************/
+/* Error type for requests that may not wait forever        */
+/************************************************************/
+#[derive(Debug, PartialEq, Eq)]
+pub enum SyncMapError<K> {
+    Poisoned,
+    WouldBlock,
+    TimedOut,
+    /// The requests waiting on each other and the loans they wait on
+    Deadlock(Diagnostics<K>),
+}
+impl<K : Debug> Display for SyncMapError<K> {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyncMapError::Poisoned => write!(f, "SyncMap has been poisioned"),
+            SyncMapError::WouldBlock => write!(f, "SyncMap request would block"),
+            SyncMapError::TimedOut => write!(f, "SyncMap request timed out"),
+            SyncMapError::Deadlock(diagnostics) => write!(f, "SyncMap request would deadlock\n{}", diagnostics),
+        }
+    }
+}
+impl<K : Debug> Error for SyncMapError<K> {
+    fn cause(&self) -> Option<&error::Error> {
+        None
+    }
+}
+impl<'a, K, T> From<PoisonError<T>> for SyncMapError<K> {
+    fn from(_ : PoisonError<T>) -> Self{
+        SyncMapError::Poisoned
+    }
+}
+
 /************************************************************/
 /* SyncMap Tests                                            */
 /************************************************************/
@@ -348,6 +675,29 @@ mod test {
         assert!(i == 9);
     }
 
+    /// Test that keys can be inserted while a loan is out without
+    /// moving the values it has loaned
+    #[test]
+    fn test_insert_while_loaned(){
+        let map = SyncMap::new();
+        map.insert(0, vec!(0,1,2)).unwrap();
+
+        let mut request = Request::new();
+        request.write(0);
+        let loan = map.request(&request).unwrap().unwrap();
+        for key in 1..100 {
+            map.insert(key, vec!(key)).unwrap();
+        }
+        loan.write(&0).unwrap().push(3);
+        assert!(**loan.write(&0).unwrap() == vec!(0,1,2,3));
+        drop(loan);
+
+        let mut request = Request::new();
+        request.read(0).read(99);
+        let loan = map.request(&request).unwrap().unwrap();
+        assert!(*loan.read(&99).unwrap() == vec!(99));
+    }
+
     /// Test if the Sync map can handle multiple threads asking
     /// for read and write permisions.
     #[test]
@@ -417,6 +767,33 @@ mod test {
         write_thread.join().unwrap();
     }
 
+    /// Test that requests which are not allowed to block give up
+    /// while the resources are loaned out, and succeed once returned
+    #[test]
+    fn try_and_timeout(){
+        let map = SyncMap::new();
+        map.insert(0, vec!(0,1,2)).unwrap();
+
+        let mut request = Request::new();
+        request.write(0);
+        let loan = map.request(&request).unwrap().unwrap();
+
+        assert!(map.try_request(&request).err() == Some(SyncMapError::WouldBlock));
+        let start = time::Instant::now();
+        let result = map.request_timeout(&request, time::Duration::from_millis(50));
+        assert!(result.err() == Some(SyncMapError::TimedOut));
+        assert!(start.elapsed() >= time::Duration::from_millis(50));
+
+        // Missing keys are still reported as None
+        let mut missing = Request::new();
+        missing.read(1);
+        assert!(map.try_request(&missing).unwrap().is_none());
+
+        drop(loan);
+        assert!(map.try_request(&request).unwrap().is_some());
+        assert!(map.request_timeout(&request, time::Duration::from_millis(50)).unwrap().is_some());
+    }
+
     #[test]
     fn multi_mut(){
         let map = Arc::new(SyncMap::new());
@@ -451,4 +828,99 @@ mod test {
             i += 1;
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn diagnostics(){
+        let map = SyncMap::new();
+        map.insert(0, vec!(0,1,2)).unwrap();
+        map.insert(1, vec!(3,4,5)).unwrap();
+
+        let mut request = Request::new();
+        request.read(0).write(1).label("system");
+        let loan = map.request(&request).unwrap().unwrap();
+
+        let diagnostics = map.diagnostics();
+        assert!(diagnostics.waiting.is_empty());
+        assert!(diagnostics.loans == vec!(LoanInfo {
+            label : Some("system".to_string()),
+            thread : thread::current().id(),
+            reads : vec!(0),
+            writes : vec!(1),
+        }));
+
+        drop(loan);
+        assert!(map.diagnostics().loans.is_empty());
+    }
+
+    /// A thread asking for resources it already holds would wait forever
+    #[test]
+    fn self_deadlock(){
+        let map = SyncMap::new();
+        map.insert(0, vec!(0,1,2)).unwrap();
+        map.set_deadlock_detection(true);
+
+        let mut request = Request::new();
+        request.write(0).label("first");
+        let loan = map.request(&request).unwrap().unwrap();
+
+        let mut again = Request::new();
+        again.read(0).label("second");
+        match map.request(&again) {
+            Err(SyncMapError::Deadlock(deadlock)) => {
+                assert!(deadlock.waiting.len() == 1);
+                assert!(deadlock.waiting[0].label == Some("second".to_string()));
+                assert!(deadlock.loans.len() == 1);
+                assert!(deadlock.loans[0].label == Some("first".to_string()));
+            },
+            _ => panic!("the request should have deadlocked"),
+        }
+        // Timed requests are allowed to wait on themselves
+        assert!(map.request_timeout(&again, time::Duration::from_millis(10)).err() == Some(SyncMapError::TimedOut));
+
+        drop(loan);
+        assert!(map.request(&again).unwrap().is_some());
+        assert!(map.diagnostics().waiting.is_empty());
+    }
+
+    /// Two threads each holding what the other is waiting on
+    #[test]
+    fn cross_deadlock(){
+        let map = Arc::new(SyncMap::new());
+        map.insert(0, vec!(0,1,2)).unwrap();
+        map.insert(1, vec!(3,4,5)).unwrap();
+        map.set_deadlock_detection(true);
+
+        let mut one = Request::new();
+        one.write(1);
+        let mut zero = Request::new();
+        zero.write(0);
+        let loan = map.request(&zero).unwrap().unwrap();
+
+        let thread_map = map.clone();
+        let handle = thread::spawn(move || {
+            let mut one = Request::new();
+            one.write(1);
+            let mut zero = Request::new();
+            zero.write(0);
+            let _loan = thread_map.request(&one).unwrap().unwrap();
+            // Waits until the main thread gives up its loan
+            thread_map.request(&zero).unwrap().is_some()
+        });
+
+        while map.diagnostics().waiting.is_empty() {
+            thread::sleep(time::Duration::from_millis(1));
+        }
+        assert!(map.find_deadlock().is_none());
+
+        match map.request(&one) {
+            Err(SyncMapError::Deadlock(deadlock)) => {
+                assert!(deadlock.waiting.len() == 2);
+                assert!(deadlock.loans.len() == 2);
+            },
+            _ => panic!("the request should have deadlocked"),
+        }
+
+        drop(loan);
+        assert!(handle.join().unwrap());
+    }
+}
@@ -1,10 +1,17 @@
 use state::Trans;
-use resources::ResourceToken;
+use resources::{ResourceToken, ResourceRequest};
 
 /// A system is a series of functions that can be called at certain times
 
 pub trait System : Send + Sync {
 
+        /// The resources this system requests during update. The Dispatcher
+        /// uses it to run systems with conflicting requests one after another,
+        /// systems that return None are run on their own
+        fn resources(&self) -> Option<&ResourceRequest> {
+            None
+        }
+
         /// While this system is in the active the State 
         /// the update function will be called once per 'frame'
         fn update(&mut self, _res : ResourceToken) -> Trans {
@@ -35,3 +42,58 @@ pub trait System : Send + Sync {
 
         }
 }
+
+/*************************************************/
+/* Describes where a system runs relative to the */
+/* other systems in a State                      */
+/*************************************************/
+pub struct SystemOrder {
+    pub(crate) name : Option<String>,
+    pub(crate) labels : Vec<String>,
+    pub(crate) before : Vec<String>,
+    pub(crate) after : Vec<String>,
+}
+
+impl SystemOrder {
+    /// Creates an order without a name or any constraints
+    pub fn new() -> SystemOrder {
+        SystemOrder {
+            name : None,
+            labels : Vec::new(),
+            before : Vec::new(),
+            after : Vec::new(),
+        }
+    }
+
+    /// Creates an order for a system with the given name, the name
+    /// can be used by other systems as a label to order against
+    pub fn named(name : &str) -> SystemOrder {
+        let mut order = SystemOrder::new();
+        order.name = Some(name.to_string());
+        order
+    }
+
+    /// Adds the system to a label shared with other systems
+    pub fn label(mut self, label : &str) -> SystemOrder {
+        self.labels.push(label.to_string());
+        self
+    }
+
+    /// The system will run before every system with the label
+    pub fn before(mut self, label : &str) -> SystemOrder {
+        self.before.push(label.to_string());
+        self
+    }
+
+    /// The system will run after every system with the label
+    pub fn after(mut self, label : &str) -> SystemOrder {
+        self.after.push(label.to_string());
+        self
+    }
+
+    /// Returns true if the system has the label or the name
+    pub(crate) fn has_label(&self, label : &str) -> bool {
+        self.name.as_ref().map_or(false, |name| name == label)
+            || self.labels.iter().any(|own| own == label)
+    }
+}
@@ -0,0 +1,85 @@
+use std::time::Duration;
+
+/*************************************************/
+/* The timing of the frame being run             */
+/*************************************************/
+/// Time is inserted into the Resources as a singleton resource by the
+/// StateMachine, systems read it by requesting `read_resource::<Time>()`.
+/// Systems run every frame use `delta`, systems run at the fixed timestep
+/// use `fixed_delta`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Time {
+    delta : Duration,
+    fixed_delta : Duration,
+    elapsed : Duration,
+    frame : u64,
+    fixed_steps : u64,
+    alpha : f64,
+}
+
+impl Time {
+    pub(crate) fn new(fixed_delta : Duration) -> Time {
+        Time {
+            delta : Duration::from_secs(0),
+            fixed_delta : fixed_delta,
+            elapsed : Duration::from_secs(0),
+            frame : 0,
+            fixed_steps : 0,
+            alpha : 0.0,
+        }
+    }
+
+    /// Returns the time the current frame covers, frames that took longer
+    /// than the maximum frame time of the StateMachine are clamped to it
+    pub fn delta(&self) -> Duration {
+        self.delta
+    }
+
+    /// Returns the delta of the current frame in seconds
+    pub fn delta_seconds(&self) -> f64 {
+        self.delta.as_secs_f64()
+    }
+
+    /// Returns the time every fixed step covers
+    pub fn fixed_delta(&self) -> Duration {
+        self.fixed_delta
+    }
+
+    /// Returns the sum of the deltas of every frame so far
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Returns the number of frames started so far, starting at 1 for the first frame
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// Returns the number of fixed steps run so far, including the current one
+    pub fn fixed_steps(&self) -> u64 {
+        self.fixed_steps
+    }
+
+    /// Returns how far the simulation is between the last fixed step and the
+    /// next one as a fraction of a step, used to interpolate what is rendered
+    pub fn alpha(&self) -> f64 {
+        self.alpha
+    }
+
+    /// Starts a new frame covering the frame time
+    pub(crate) fn start_frame(&mut self, frame_time : Duration) {
+        self.delta = frame_time;
+        self.elapsed += frame_time;
+        self.frame += 1;
+    }
+
+    /// Counts a fixed step
+    pub(crate) fn start_fixed_step(&mut self) {
+        self.fixed_steps += 1;
+    }
+
+    /// Sets the time left over after the fixed steps of the frame
+    pub(crate) fn set_remainder(&mut self, remainder : Duration) {
+        self.alpha = remainder.as_secs_f64() / self.fixed_delta.as_secs_f64();
+    }
+}